@@ -179,6 +179,7 @@ pub enum Type<'a> {
     Byte,
     SingleByte,
     Halfword,
+    SingleHalfword,
 
     /// Aggregate type with a specified name
     Aggregate(&'a TypeDef<'a>),
@@ -210,6 +211,7 @@ impl<'a> Type<'a> {
             // TODO: fix alignments for single variants
             Self::SingleByte => 1,
             Self::Halfword => 2,
+            Self::SingleHalfword => 2,
             Self::SingleWord => 2,
             Self::Word | Self::Single => 4,
             Self::Long | Self::Double => 8,
@@ -232,6 +234,7 @@ impl<'a> fmt::Display for Type<'a> {
             Self::SingleByte => write!(f, "sb"),
             Self::SingleWord => write!(f, "sw"),
             Self::Halfword => write!(f, "h"),
+            Self::SingleHalfword => write!(f, "sh"),
             Self::Word => write!(f, "w"),
             Self::Long => write!(f, "l"),
             Self::Single => write!(f, "s"),
@@ -299,7 +302,10 @@ impl<'a> fmt::Display for DataDef<'a> {
             "{{ {} }}",
             self.items
                 .iter()
-                .map(|(ty, item)| format!("{} {}", ty, item))
+                .map(|(ty, item)| match item {
+                    DataItem::Zero(_) => format!("{}", item),
+                    _ => format!("{} {}", ty, item),
+                })
                 .collect::<Vec<String>>()
                 .join(", ")
         )
@@ -315,6 +321,8 @@ pub enum DataItem {
     Str(String),
     /// Constant
     Const(u64),
+    /// Zero-initialized area of the given size in bytes, its type is ignored
+    Zero(u64),
 }
 
 impl fmt::Display for DataItem {
@@ -326,6 +334,7 @@ impl fmt::Display for DataItem {
             },
             Self::Str(string) => write!(f, "\"{}\"", string),
             Self::Const(val) => write!(f, "{}", val),
+            Self::Zero(size) => write!(f, "z {}", size),
         }
     }
 }
@@ -69,6 +69,23 @@ fn instr_blit() {
     assert_eq!(lines.next().unwrap(), "\tblit %src, %dst, 4");
 }
 
+#[test]
+fn instr_load_signed_halfword() {
+    let blk = Block {
+        label: "start".into(),
+        statements: vec![Statement::Assign(
+            Value::Temporary("cell".into()),
+            Type::Word,
+            Instr::Load(Type::SingleHalfword, Value::Temporary("ptr".into())),
+        )],
+    };
+
+    let formatted = format!("{}", blk);
+    let mut lines = formatted.lines();
+    assert_eq!(lines.next().unwrap(), "@start");
+    assert_eq!(lines.next().unwrap(), "\t%cell =w loadsh %ptr");
+}
+
 #[test]
 fn function() {
     let func = Function {
@@ -124,6 +141,22 @@ fn datadef() {
     );
 }
 
+#[test]
+fn datadef_zero() {
+    let datadef = DataDef::new(
+        Linkage::private(),
+        "tape",
+        Some(8),
+        vec![
+            (Type::Byte, DataItem::Const(1)),
+            (Type::Byte, DataItem::Zero(30000)),
+        ],
+    );
+
+    let formatted = format!("{}", datadef);
+    assert_eq!(formatted, "data $tape = align 8 { b 1, z 30000 }");
+}
+
 #[test]
 fn datadef_new_equivalence() {
     let datadef1 = DataDef {
@@ -158,6 +191,7 @@ fn typedef() {
 fn type_size() {
     assert!(Type::Byte.size() == 1);
     assert!(Type::Halfword.size() == 2);
+    assert!(Type::SingleHalfword.size() == 2);
     assert!(Type::Word.size() == 4);
     assert!(Type::Single.size() == 4);
     assert!(Type::Long.size() == 8);
@@ -0,0 +1,145 @@
+use crate::{BracketTable, BrainfuckToken, CompressedBrainfuckToken};
+use std::fmt;
+
+/// Mid-level representation of a brainfuck program, offsets are relative to the tape pointer
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum BfIr {
+    /// Adds `delta` to the cell at `offset`, wrapping around the cell width
+    AddCell { offset: i64, delta: i64 },
+    /// Moves the tape pointer by `delta` cells
+    MovePtr { delta: i64 },
+    /// Overwrites the cell at `offset` with `value`
+    SetCell { offset: i64, value: i64 },
+    /// Adds the cell at `src` times `factor` to the cell at `offset`
+    MulAdd { offset: i64, src: i64, factor: i64 },
+    /// Moves the tape pointer by `stride` cells until it lands on a zero cell, blaming the `[`
+    /// at byte `source` of the program when it runs off the tape
+    Scan { stride: i64, source: usize },
+    /// Runs `body` while the cell under the tape pointer is not zero, `source` is the byte of
+    /// its `[` in the program
+    Loop { body: Vec<BfIr>, source: usize },
+    /// Writes the cell at `offset` to the output `count` times
+    Output { offset: i64, count: u64 },
+    /// Reads a byte from the input into the cell at `offset`
+    Input { offset: i64 },
+    /// Stops the program when the cell at `offset` is outside the tape, blaming the command
+    /// at byte `source` of the program
+    BoundsCheck { offset: i64, source: usize },
+    /// Writes `bytes` to the output, what the program printed before running it was needed
+    Write { bytes: Vec<u8> },
+}
+
+pub trait BfIrLowerable {
+    /// With `checked` every pointer movement is followed by a bounds check
+    fn to_bfir(&self, brackets: &BracketTable, checked: bool) -> Vec<BfIr>;
+}
+
+impl BfIrLowerable for [CompressedBrainfuckToken] {
+    fn to_bfir(&self, brackets: &BracketTable, checked: bool) -> Vec<BfIr> {
+        // Bodies of the loops currently being built, the outermost one is the program itself
+        let mut bodies: Vec<Vec<BfIr>> = vec![vec![]];
+        for (index, currtoken) in self.iter().enumerate() {
+            let node = match currtoken.token {
+                BrainfuckToken::Next => BfIr::MovePtr {
+                    delta: currtoken.num as i64,
+                },
+                BrainfuckToken::Prev => BfIr::MovePtr {
+                    delta: -(currtoken.num as i64),
+                },
+                BrainfuckToken::Add => BfIr::AddCell {
+                    offset: 0,
+                    delta: currtoken.num as i64,
+                },
+                BrainfuckToken::Sub => BfIr::AddCell {
+                    offset: 0,
+                    delta: -(currtoken.num as i64),
+                },
+                BrainfuckToken::Out => BfIr::Output {
+                    offset: 0,
+                    count: currtoken.num,
+                },
+                BrainfuckToken::Input => BfIr::Input { offset: 0 },
+                // Unmatched brackets only get here with --no-check, treat them as no-ops
+                BrainfuckToken::LoopStart if brackets.partner(index).is_some() => {
+                    bodies.push(vec![]);
+                    continue;
+                }
+                BrainfuckToken::LoopEnd => match brackets.partner(index) {
+                    Some(start) => BfIr::Loop {
+                        body: bodies.pop().unwrap(),
+                        source: self[start].span.offset,
+                    },
+                    None => continue,
+                },
+                BrainfuckToken::LoopStart | BrainfuckToken::Invalid => continue,
+            };
+            let moves = matches!(node, BfIr::MovePtr { .. });
+            bodies.last_mut().unwrap().push(node);
+            if checked && moves {
+                bodies.last_mut().unwrap().push(BfIr::BoundsCheck {
+                    offset: 0,
+                    source: currtoken.span.offset,
+                });
+            }
+        }
+        bodies.pop().unwrap()
+    }
+}
+
+fn fmt_offset(offset: i64) -> String {
+    if offset == 0 {
+        "[p]".to_owned()
+    } else {
+        format!("[p{:+}]", offset)
+    }
+}
+
+fn fmt_nodes(f: &mut fmt::Formatter, nodes: &[BfIr], depth: usize) -> fmt::Result {
+    for node in nodes {
+        write!(f, "{}", "    ".repeat(depth))?;
+        match node {
+            BfIr::AddCell { offset, delta } => {
+                writeln!(f, "add {} {:+}", fmt_offset(*offset), delta)?
+            }
+            BfIr::MovePtr { delta } => writeln!(f, "move {:+}", delta)?,
+            BfIr::SetCell { offset, value } => {
+                writeln!(f, "set {} {}", fmt_offset(*offset), value)?
+            }
+            BfIr::MulAdd {
+                offset,
+                src,
+                factor,
+            } => writeln!(
+                f,
+                "muladd {} {} *{}",
+                fmt_offset(*offset),
+                fmt_offset(*src),
+                factor
+            )?,
+            BfIr::Scan { stride, .. } => writeln!(f, "scan {:+}", stride)?,
+            BfIr::Loop { body, .. } => {
+                writeln!(f, "loop {{")?;
+                fmt_nodes(f, body, depth + 1)?;
+                writeln!(f, "{}}}", "    ".repeat(depth))?;
+            }
+            BfIr::Output { offset, count } if *count == 1 => {
+                writeln!(f, "output {}", fmt_offset(*offset))?
+            }
+            BfIr::Output { offset, count } => {
+                writeln!(f, "output {} x{}", fmt_offset(*offset), count)?
+            }
+            BfIr::Input { offset } => writeln!(f, "input {}", fmt_offset(*offset))?,
+            BfIr::BoundsCheck { offset, source } => {
+                writeln!(f, "check {} at {}", fmt_offset(*offset), source)?
+            }
+            BfIr::Write { bytes } => writeln!(f, "write b\"{}\"", bytes.escape_ascii())?,
+        }
+    }
+    Ok(())
+}
+
+impl fmt::Display for BfIr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_nodes(f, std::slice::from_ref(self), 0)
+    }
+}
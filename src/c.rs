@@ -0,0 +1,256 @@
+//! Portable C backend, for machines with a C compiler but no QBE
+
+use crate::{BfIr, Eof, Tape, TapeMode, OUT_OF_BOUNDS_EXIT_CODE};
+use std::fmt::Write;
+
+pub trait CLowerable {
+    fn to_c(&self, tape: Tape) -> String;
+}
+
+impl CLowerable for [BfIr] {
+    fn to_c(&self, tape: Tape) -> String {
+        let mut program = CProgram {
+            tape,
+            body: String::new(),
+            depth: 1,
+            checks_bounds: false,
+        };
+        program.lower(self);
+
+        // Programs folded down to what they write never touch the tape
+        let uses_tape = self.iter().any(|node| !matches!(node, BfIr::Write { .. }));
+        let mut source = String::new();
+        program.prelude(&mut source, uses_tape);
+        source.push_str("int main(void) {\n");
+        if uses_tape && tape.mode != TapeMode::Fixed {
+            writeln!(
+                source,
+                "    tape = calloc({}, sizeof(cell));\n    tape_length = {};",
+                tape.size, tape.size
+            )
+            .unwrap();
+        }
+        if uses_tape {
+            source.push_str("    ptrdiff_t p = 0;\n");
+        }
+        source.push_str(&program.body);
+        source.push_str("    return 0;\n}\n");
+        source
+    }
+}
+
+struct CProgram {
+    tape: Tape,
+    /// Statements of `main` so far
+    body: String,
+    /// Indentation level of the next statement
+    depth: usize,
+    /// Whether `qbfc_out_of_bounds` or `qbfc_grow_tape` has to be defined
+    checks_bounds: bool,
+}
+
+impl CProgram {
+    fn line(&mut self, statement: &str) {
+        writeln!(self.body, "{}{}", "    ".repeat(self.depth), statement).unwrap();
+    }
+
+    fn lower(&mut self, program: &[BfIr]) {
+        for node in program {
+            match node {
+                BfIr::MovePtr { delta } => self.line(&format!("{};", move_pointer(*delta))),
+                BfIr::AddCell { offset, delta } if *delta < 0 => self.line(&format!(
+                    "{} -= {}u;",
+                    cell(*offset),
+                    self.tape.wrap(delta.wrapping_neg())
+                )),
+                BfIr::AddCell { offset, delta } => self.line(&format!(
+                    "{} += {}u;",
+                    cell(*offset),
+                    self.tape.wrap(*delta)
+                )),
+                BfIr::SetCell { offset, value } => {
+                    self.line(&format!("{} = {}u;", cell(*offset), self.tape.wrap(*value)))
+                }
+                BfIr::MulAdd {
+                    offset,
+                    src,
+                    factor,
+                } => {
+                    // The unsigned factor keeps narrow cells from being multiplied as signed ints
+                    let statement = match self.tape.wrap(*factor) {
+                        1 => format!("{} += {};", cell(*offset), cell(*src)),
+                        factor if factor == self.tape.wrap(-1) => {
+                            format!("{} -= {};", cell(*offset), cell(*src))
+                        }
+                        factor => format!("{} += {} * {}u;", cell(*offset), cell(*src), factor),
+                    };
+                    self.line(&statement);
+                }
+                BfIr::Scan { stride: 1, source } if self.tape.scans_bytes() => {
+                    self.line("{");
+                    self.depth += 1;
+                    self.line(&format!(
+                        "cell *zero = memchr(tape + p, 0, {} - p);",
+                        self.tape.size
+                    ));
+                    // Without a zero cell the scan runs off the end of the tape
+                    self.line(&format!("p = zero ? zero - tape : {};", self.tape.size));
+                    self.depth -= 1;
+                    self.line("}");
+                    self.bounds_check(0, *source);
+                }
+                BfIr::Scan { stride, .. } => {
+                    self.line(&format!("while (tape[p]) {};", move_pointer(*stride)))
+                }
+                BfIr::Loop { body, .. } => {
+                    self.line("while (tape[p]) {");
+                    self.depth += 1;
+                    self.lower(body);
+                    self.depth -= 1;
+                    self.line("}");
+                }
+                BfIr::Output { offset, count: 1 } => {
+                    self.line(&format!("putchar({});", cell(*offset)))
+                }
+                BfIr::Output { offset, count } => self.line(&format!(
+                    "for (size_t i = 0; i < {}; i++) putchar({});",
+                    count,
+                    cell(*offset)
+                )),
+                BfIr::Input { offset } => self.input(*offset),
+                BfIr::BoundsCheck { offset, source } => self.bounds_check(*offset, *source),
+                // C99 compilers only have to take string literals up to 4095 bytes long
+                BfIr::Write { bytes } => {
+                    for chunk in bytes.chunks(4095) {
+                        self.line(&format!(
+                            "fwrite(\"{}\", 1, {}, stdout);",
+                            escape_bytes(chunk),
+                            chunk.len()
+                        ));
+                    }
+                }
+            }
+        }
+    }
+
+    fn input(&mut self, offset: i64) {
+        self.line("{");
+        self.depth += 1;
+        // stdio only flushes stdout before reading when it is a terminal
+        self.line("fflush(stdout);");
+        self.line("int c = getchar();");
+        match self.tape.eof {
+            Eof::MinusOne => self.line(&format!("{} = c;", cell(offset))),
+            Eof::Zero => self.line(&format!("{} = c == EOF ? 0 : c;", cell(offset))),
+            Eof::Unchanged => self.line(&format!("if (c != EOF) {} = c;", cell(offset))),
+        }
+        self.depth -= 1;
+        self.line("}");
+    }
+
+    /// Calls `qbfc_out_of_bounds` or `qbfc_grow_tape` when the index of the cell at `offset`
+    /// is off the tape
+    fn bounds_check(&mut self, offset: i64, source: usize) {
+        self.checks_bounds = true;
+        let index = index(offset);
+        let length = match self.tape.mode {
+            TapeMode::Fixed => self.tape.size.to_string(),
+            TapeMode::Grow | TapeMode::GrowBoth => "(ptrdiff_t)tape_length".to_owned(),
+        };
+        match self.tape.mode {
+            TapeMode::Fixed => self.line(&format!(
+                "if ({index} < 0 || {index} >= {length}) qbfc_out_of_bounds({source});"
+            )),
+            TapeMode::Grow => {
+                self.line(&format!("if ({index} < 0) qbfc_out_of_bounds({source});"));
+                self.line(&format!(
+                    "if ({index} >= {length}) qbfc_grow_tape({index});"
+                ));
+            }
+            TapeMode::GrowBoth => self.line(&format!(
+                "if ({index} < 0 || {index} >= {length}) p += qbfc_grow_tape({index});"
+            )),
+        }
+    }
+
+    /// Includes, the tape and whichever helpers the body calls
+    fn prelude(&self, source: &mut String, uses_tape: bool) {
+        source.push_str(
+            "#include <stddef.h>\n#include <stdint.h>\n#include <stdio.h>\n\
+             #include <stdlib.h>\n#include <string.h>\n\n",
+        );
+        writeln!(source, "typedef uint{}_t cell;\n", self.tape.cell_bits).unwrap();
+        match self.tape.mode {
+            _ if !uses_tape => (),
+            TapeMode::Fixed => writeln!(source, "static cell tape[{}];\n", self.tape.size).unwrap(),
+            TapeMode::Grow | TapeMode::GrowBoth => {
+                source.push_str("static cell *tape;\nstatic size_t tape_length;\n\n")
+            }
+        }
+
+        if self.checks_bounds && self.tape.mode != TapeMode::GrowBoth {
+            writeln!(
+                source,
+                "static void qbfc_out_of_bounds(size_t source) {{\n    \
+                 fflush(stdout);\n    \
+                 fprintf(stderr, \"tape pointer out of bounds at source offset %zu\\n\", source);\n    \
+                 exit({OUT_OF_BOUNDS_EXIT_CODE});\n}}\n"
+            )
+            .unwrap();
+        }
+
+        if self.checks_bounds && self.tape.mode != TapeMode::Fixed {
+            // `Tape::growth` on the index, returns how far the old cells moved
+            source.push_str(
+                "static ptrdiff_t qbfc_grow_tape(ptrdiff_t index) {\n    \
+                 size_t length = tape_length, extra;\n    \
+                 ptrdiff_t moved = 0;\n    \
+                 if (index < 0) {\n        \
+                 extra = (size_t)-index > length ? (size_t)-index : length;\n        \
+                 moved = extra;\n    \
+                 } else {\n        \
+                 extra = (size_t)index + 1 > 2 * length ? (size_t)index + 1 - length : length;\n    \
+                 }\n    \
+                 tape = realloc(tape, (length + extra) * sizeof(cell));\n    \
+                 memmove(tape + moved, tape, length * sizeof(cell));\n    \
+                 memset(index < 0 ? tape : tape + length, 0, extra * sizeof(cell));\n    \
+                 tape_length = length + extra;\n    \
+                 return moved;\n}\n\n",
+            );
+        }
+    }
+}
+
+/// Index of the cell `offset` cells away from the tape pointer
+fn index(offset: i64) -> String {
+    match offset {
+        0 => "p".to_owned(),
+        ..0 => format!("p - {}", offset.unsigned_abs()),
+        _ => format!("p + {}", offset),
+    }
+}
+
+fn move_pointer(delta: i64) -> String {
+    if delta < 0 {
+        format!("p -= {}", delta.unsigned_abs())
+    } else {
+        format!("p += {}", delta)
+    }
+}
+
+fn cell(offset: i64) -> String {
+    format!("tape[{}]", index(offset))
+}
+
+/// Spells `bytes` out for a C string literal, anything that is not plain printable ASCII
+/// becomes a three digit octal escape so the digits after it are not read as part of it, and
+/// `?` is escaped too so no trigraph can form
+fn escape_bytes(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|&byte| match byte {
+            b' '..=b'~' if !matches!(byte, b'"' | b'\\' | b'?') => (byte as char).to_string(),
+            _ => format!("\\{:03o}", byte),
+        })
+        .collect()
+}
@@ -0,0 +1,45 @@
+use crate::token::Span;
+
+/// An error pointing at a location in the brainfuck source
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Diagnostic {
+    pub message: String,
+    pub span: Span,
+}
+
+impl Diagnostic {
+    pub fn new(message: impl Into<String>, span: Span) -> Self {
+        Diagnostic {
+            message: message.into(),
+            span,
+        }
+    }
+
+    /// Renders the diagnostic with the offending source line and a caret under the column
+    pub fn render(&self, filename: &str, source: &[u8]) -> String {
+        let line_start = source[..self.span.offset]
+            .iter()
+            .rposition(|byte| *byte == b'\n')
+            .map_or(0, |newline| newline + 1);
+        let line_end = source[self.span.offset..]
+            .iter()
+            .position(|byte| *byte == b'\n')
+            .map_or(source.len(), |newline| self.span.offset + newline);
+        let line = String::from_utf8_lossy(&source[line_start..line_end]);
+
+        // Keep tabs so the caret lines up with the snippet in any terminal
+        let padding: String = String::from_utf8_lossy(&source[line_start..self.span.offset])
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        let gutter = " ".repeat(self.span.line.to_string().len());
+        format!(
+            "error: {message}\n{gutter}--> {filename}:{line_num}:{column}\n{gutter} |\n{line_num} | {line}\n{gutter} | {padding}^",
+            message = self.message,
+            line_num = self.span.line,
+            column = self.span.column,
+            line = line.trim_end_matches('\r'),
+        )
+    }
+}
@@ -0,0 +1,80 @@
+use crate::diagnostic::Diagnostic;
+use crate::token::Span;
+use crate::OUT_OF_BOUNDS_EXIT_CODE;
+use std::fmt;
+use std::io;
+use std::process::ExitStatus;
+
+/// Everything that can stop qbfc, each kind with its own exit status
+#[derive(Debug)]
+pub enum QbfcError {
+    /// The program has brackets without a partner
+    UnmatchedBrackets(Vec<Diagnostic>),
+    /// Options that cannot be used together
+    Usage(String),
+    /// The interpreted program moved its tape pointer off the tape at this span
+    OutOfBounds(Span),
+    /// Reading the program or writing the output failed
+    Io { action: String, err: io::Error },
+    /// A tool like qbe or cc could not be started
+    Spawn { tool: String, err: io::Error },
+    /// A tool like qbe or cc ran and failed, what it said went to our stderr
+    ToolFailed { tool: String, status: ExitStatus },
+}
+
+impl QbfcError {
+    pub fn io(action: impl Into<String>, err: io::Error) -> Self {
+        QbfcError::Io {
+            action: action.into(),
+            err,
+        }
+    }
+
+    /// Exit status of qbfc, 2 is what clap exits with on bad arguments and 3 what the
+    /// compiled programs exit with when they leave the tape
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            QbfcError::UnmatchedBrackets(_) => 1,
+            QbfcError::Usage(_) => 2,
+            QbfcError::OutOfBounds(_) => OUT_OF_BOUNDS_EXIT_CODE,
+            QbfcError::Io { .. } => 4,
+            QbfcError::Spawn { .. } => 5,
+            QbfcError::ToolFailed { .. } => 6,
+        }
+    }
+
+    /// Prints the error to stderr, `render` shows a diagnostic with the source lines it points at
+    pub fn report(&self, render: impl Fn(&Diagnostic) -> String) {
+        match self {
+            QbfcError::UnmatchedBrackets(diagnostics) => {
+                for diagnostic in diagnostics {
+                    eprintln!("{}\n", render(diagnostic));
+                }
+                eprintln!("error: {}", self);
+            }
+            QbfcError::OutOfBounds(span) => {
+                eprintln!("{}", render(&Diagnostic::new(self.to_string(), *span)))
+            }
+            _ => eprintln!("error: {}", self),
+        }
+    }
+}
+
+impl fmt::Display for QbfcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QbfcError::UnmatchedBrackets(diagnostics) => write!(
+                f,
+                "aborting due to {} unmatched bracket(s)",
+                diagnostics.len()
+            ),
+            QbfcError::Usage(message) => write!(f, "{}", message),
+            QbfcError::OutOfBounds(_) => write!(f, "tape pointer out of bounds"),
+            QbfcError::Io { action, err } => write!(f, "{}: {}", action, err),
+            QbfcError::Spawn { tool, err } => write!(f, "failed to run {}: {}", tool, err),
+            QbfcError::ToolFailed { tool, status } => write!(f, "{} failed, {}", tool, status),
+        }
+    }
+}
+
+impl std::error::Error for QbfcError {}
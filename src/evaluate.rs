@@ -0,0 +1,159 @@
+use crate::{BfIr, Tape};
+
+/// Nodes, loop iterations and written bytes the compile time run may go through
+const STEP_BUDGET: u64 = 1 << 24;
+
+pub trait ConstantFoldable {
+    fn fold_constant_output(self, tape: Tape) -> Self;
+}
+
+impl ConstantFoldable for Vec<BfIr> {
+    /// Runs the start of the program at compile time, up to the first input or until the
+    /// step budget runs out, and replaces it with what it wrote and the cells it left behind
+    fn fold_constant_output(self, tape: Tape) -> Self {
+        let mut evaluator = Evaluator {
+            tape,
+            cells: vec![],
+            pointer: 0,
+            output: vec![],
+            steps: STEP_BUDGET,
+        };
+
+        // Top-level nodes run entirely or not at all, the generated code can not resume from
+        // the middle of a loop
+        let mut evaluated = 0;
+        for node in &self {
+            let (cells, pointer, written) = (
+                evaluator.cells.clone(),
+                evaluator.pointer,
+                evaluator.output.len(),
+            );
+            if evaluator.run(std::slice::from_ref(node)).is_none() {
+                (evaluator.cells, evaluator.pointer) = (cells, pointer);
+                evaluator.output.truncate(written);
+                break;
+            }
+            evaluated += 1;
+        }
+
+        let mut folded: Vec<BfIr> = vec![];
+        if !evaluator.output.is_empty() {
+            folded.push(BfIr::Write {
+                bytes: evaluator.output,
+            });
+        }
+        let rest = &self[evaluated..];
+        if rest.is_empty() {
+            return folded;
+        }
+
+        folded.extend(
+            evaluator
+                .cells
+                .iter()
+                .enumerate()
+                .filter(|(_, value)| **value != 0)
+                .map(|(offset, value)| BfIr::SetCell {
+                    offset: offset as i64,
+                    value: *value as i64,
+                }),
+        );
+        if evaluator.pointer != 0 {
+            folded.push(BfIr::MovePtr {
+                delta: evaluator.pointer,
+            });
+        }
+        folded.extend_from_slice(rest);
+        folded
+    }
+}
+
+/// Runs BFIR on a copy of the tape, giving up with `None` wherever the program needs
+/// something only known at runtime
+struct Evaluator {
+    tape: Tape,
+    /// Cells from the start of the tape up to the last one written, the rest are zero
+    cells: Vec<u64>,
+    pointer: i64,
+    output: Vec<u8>,
+    steps: u64,
+}
+
+impl Evaluator {
+    fn step(&mut self, steps: u64) -> Option<()> {
+        self.steps = self.steps.checked_sub(steps)?;
+        Some(())
+    }
+
+    /// Index of the cell `offset` cells away from the pointer, out of bounds is left for the
+    /// generated code to deal with
+    fn index(&self, offset: i64) -> Option<usize> {
+        usize::try_from(self.pointer + offset)
+            .ok()
+            .filter(|index| *index < self.tape.size)
+    }
+
+    fn get(&self, offset: i64) -> Option<u64> {
+        let index = self.index(offset)?;
+        Some(self.cells.get(index).copied().unwrap_or(0))
+    }
+
+    fn set(&mut self, offset: i64, value: i64) -> Option<()> {
+        let index = self.index(offset)?;
+        if index >= self.cells.len() {
+            self.cells.resize(index + 1, 0);
+        }
+        self.cells[index] = self.tape.wrap(value);
+        Some(())
+    }
+
+    fn run(&mut self, program: &[BfIr]) -> Option<()> {
+        for node in program {
+            self.step(1)?;
+            match node {
+                BfIr::AddCell { offset, delta } => {
+                    let value = self.get(*offset)? as i64;
+                    self.set(*offset, value.wrapping_add(*delta))?;
+                }
+                BfIr::MovePtr { delta } => self.pointer += delta,
+                BfIr::SetCell { offset, value } => self.set(*offset, *value)?,
+                BfIr::MulAdd {
+                    offset,
+                    src,
+                    factor,
+                } => {
+                    let product = (self.get(*src)? as i64).wrapping_mul(*factor);
+                    let value = self.get(*offset)? as i64;
+                    self.set(*offset, value.wrapping_add(product))?;
+                }
+                BfIr::Scan { stride, .. } => {
+                    while self.get(0)? != 0 {
+                        self.step(1)?;
+                        self.pointer += stride;
+                    }
+                }
+                BfIr::Loop { body, .. } => {
+                    while self.get(0)? != 0 {
+                        self.step(1)?;
+                        self.run(body)?;
+                    }
+                }
+                BfIr::Output { offset, count } => {
+                    let value = self.get(*offset)? as u8;
+                    self.step(*count)?;
+                    self.output
+                        .extend(std::iter::repeat_n(value, *count as usize));
+                }
+                BfIr::Input { .. } => return None,
+                BfIr::BoundsCheck { offset, .. } => {
+                    self.index(*offset)?;
+                }
+                BfIr::Write { bytes } => {
+                    self.step(bytes.len() as u64)?;
+                    self.output.extend_from_slice(bytes);
+                }
+            }
+        }
+        Some(())
+    }
+}
@@ -0,0 +1,132 @@
+use crate::{BracketTable, BrainfuckToken, CompressedBrainfuckToken, Eof, Span, Tape, TapeMode};
+use std::io::{self, Read, Write};
+
+#[derive(Debug)]
+pub enum InterpreterError {
+    Io(io::Error),
+    /// A cell outside of the tape was accessed by the token at this span
+    PointerOutOfBounds(Span),
+}
+
+impl From<io::Error> for InterpreterError {
+    fn from(err: io::Error) -> Self {
+        InterpreterError::Io(err)
+    }
+}
+
+/// Reference interpreter with the same semantics as the generated QBE program
+pub struct Interpreter {
+    layout: Tape,
+    tape: Vec<u64>,
+    pointer: i64,
+    /// Whether the pointer is checked every time it moves, like `--checked` programs do
+    checked: bool,
+}
+
+impl Interpreter {
+    pub fn new(layout: Tape, checked: bool) -> Self {
+        Interpreter {
+            layout,
+            tape: vec![0; layout.size],
+            pointer: 0,
+            checked,
+        }
+    }
+
+    fn cell(&mut self, span: Span) -> Result<&mut u64, InterpreterError> {
+        let length = self.tape.len() as i64;
+        if self.pointer >= length && self.layout.mode != TapeMode::Fixed {
+            let growth = Tape::growth(length as u64, self.pointer);
+            self.tape.resize((length as u64 + growth) as usize, 0);
+        } else if self.pointer < 0 && self.layout.mode == TapeMode::GrowBoth {
+            let growth = Tape::growth(length as u64, self.pointer);
+            self.tape
+                .splice(0..0, std::iter::repeat_n(0, growth as usize));
+            self.pointer += growth as i64;
+        }
+
+        usize::try_from(self.pointer)
+            .ok()
+            .and_then(|pointer| self.tape.get_mut(pointer))
+            .ok_or(InterpreterError::PointerOutOfBounds(span))
+    }
+
+    /// Moves the pointer, checking that it stays on the tape or growing the tape when the
+    /// compiled program would
+    fn step(&mut self, delta: i64, span: Span) -> Result<(), InterpreterError> {
+        self.pointer += delta;
+        if self.checked || self.layout.mode != TapeMode::Fixed {
+            self.cell(span)?;
+        }
+        Ok(())
+    }
+
+    /// Adds `delta` to the current cell, wrapping around the cell width
+    fn add(&mut self, delta: i64, span: Span) -> Result<(), InterpreterError> {
+        let layout = self.layout;
+        let cell = self.cell(span)?;
+        *cell = layout.wrap((*cell as i64).wrapping_add(delta));
+        Ok(())
+    }
+
+    pub fn run(
+        &mut self,
+        compressed_tokens: &[CompressedBrainfuckToken],
+        brackets: &BracketTable,
+        input: &mut impl Read,
+        output: &mut impl Write,
+    ) -> Result<(), InterpreterError> {
+        let mut index: usize = 0;
+        while index < compressed_tokens.len() {
+            let currtoken = &compressed_tokens[index];
+            match currtoken.token {
+                BrainfuckToken::Next => self.step(currtoken.num as i64, currtoken.span)?,
+                BrainfuckToken::Prev => self.step(-(currtoken.num as i64), currtoken.span)?,
+                BrainfuckToken::Add => self.add(currtoken.num as i64, currtoken.span)?,
+                BrainfuckToken::Sub => {
+                    self.add((currtoken.num as i64).wrapping_neg(), currtoken.span)?
+                }
+                BrainfuckToken::Out => {
+                    // Only the low byte gets written, like `putchar` does
+                    let value = *self.cell(currtoken.span)? as u8;
+                    for _ in 0..currtoken.num {
+                        output.write_all(&[value])?;
+                    }
+                }
+                BrainfuckToken::Input => {
+                    // `output` may be buffered, the program has to have printed everything it
+                    // wrote before we block on reading
+                    output.flush()?;
+                    let mut byte = [0];
+                    let eof = input.read(&mut byte)? == 0;
+                    let layout = self.layout;
+                    let cell = self.cell(currtoken.span)?;
+                    *cell = match layout.eof {
+                        _ if !eof => u64::from(byte[0]),
+                        Eof::Unchanged => *cell,
+                        Eof::Zero => 0,
+                        Eof::MinusOne => layout.wrap(-1),
+                    };
+                }
+                BrainfuckToken::LoopStart => {
+                    if let Some(loop_end) = brackets.partner(index) {
+                        if *self.cell(currtoken.span)? == 0 {
+                            index = loop_end;
+                        }
+                    }
+                }
+                BrainfuckToken::LoopEnd => {
+                    if let Some(loop_start) = brackets.partner(index) {
+                        // Land on the `[` so the condition gets checked again
+                        index = loop_start;
+                        continue;
+                    }
+                }
+                BrainfuckToken::Invalid => (),
+            }
+            index += 1;
+        }
+        output.flush()?;
+        Ok(())
+    }
+}
@@ -1,9 +1,15 @@
-use crate::{BrainfuckToken, CompressedBrainfuckToken};
+use crate::runtime::*;
+use crate::{BfIr, Eof, Tape, TapeMode, Target, OUT_OF_BOUNDS_EXIT_CODE};
 
 pub struct QBEIr<'a> {
     pub program: qbe::Module<'a>,
     main_func: qbe::Function<'a>,
     blocks: BlocksTracker<'a>,
+    varsubindex: usize,
+    tape: Tape,
+    target: Target,
+    /// Whether any bounds check needs the out of bounds routine
+    checks_bounds: bool,
 }
 
 struct BlocksTracker<'a> {
@@ -13,20 +19,23 @@ struct BlocksTracker<'a> {
 
 impl<'a> BlocksTracker<'a> {
     fn new() -> Self {
-        return BlocksTracker {
+        BlocksTracker {
             blocks: vec![],
             idx: 1,
-        };
+        }
     }
 
-    fn add_block(self: &mut Self, block: qbe::Block<'a>) {
+    fn add_block(&mut self, block: qbe::Block<'a>) {
         self.blocks.push(block);
         self.idx += 1;
     }
 }
 
+const TAPE_NAME: &str = "tape";
+const STACK_POINTER_NAME: &str = "stackptr";
+
 impl<'a> QBEIr<'a> {
-    pub fn new() -> Self {
+    pub fn new(tape: Tape, target: Target) -> Self {
         QBEIr {
             program: qbe::Module::new(),
             main_func: qbe::Function::new(
@@ -40,513 +49,660 @@ impl<'a> QBEIr<'a> {
                 Some(qbe::Type::Word),
             ),
             blocks: BlocksTracker::new(),
+            varsubindex: 0,
+            tape,
+            target,
+            checks_bounds: false,
         }
     }
 
-    pub fn init_body(self: &mut Self) -> &mut Self {
-        let stack_name = String::from("stack");
-        let stack_pointer_name = String::from("stackptr");
+    pub fn init_body(&mut self) -> &mut Self {
         let mut startblock = qbe::Block {
             label: format!("start.{}", self.blocks.idx),
             statements: vec![],
         };
 
-        startblock.assign_instr(
-            qbe::Value::Temporary(stack_name.to_owned()),
-            qbe::Type::Long,
-            qbe::Instr::Alloc4(30000),
-        );
+        let tape_bytes = self.tape.size as u64 * self.tape.cell_bytes();
+        let tape = if self.tape.mode == TapeMode::Fixed {
+            // A zero-initialized global keeps big tapes off the stack and spares us clearing it
+            self.program.add_data(qbe::DataDef::new(
+                qbe::Linkage::private(),
+                TAPE_NAME,
+                (self.tape.cell_bytes() > 1).then_some(self.tape.cell_bytes()),
+                vec![(qbe::Type::Byte, qbe::DataItem::Zero(tape_bytes))],
+            ));
+            qbe::Value::Global(TAPE_NAME.to_owned())
+        } else {
+            // A tape that grows lives on the heap, with its bounds where the checks can find them
+            for bound in [TAPE_START_NAME, TAPE_END_NAME] {
+                self.program.add_data(qbe::DataDef::new(
+                    qbe::Linkage::private(),
+                    bound,
+                    None,
+                    vec![(qbe::Type::Long, qbe::DataItem::Const(0))],
+                ));
+            }
+            let tape = qbe::Value::Temporary(TAPE_NAME.to_owned());
+            startblock.assign_instr(
+                tape.clone(),
+                qbe::Type::Long,
+                qbe::Instr::Call(
+                    "calloc".to_owned(),
+                    vec![
+                        (qbe::Type::Long, qbe::Value::Const(self.tape.size as u64)),
+                        (qbe::Type::Long, qbe::Value::Const(self.tape.cell_bytes())),
+                    ],
+                ),
+            );
+            startblock.assign_instr(
+                qbe::Value::Temporary("tape_end".to_owned()),
+                qbe::Type::Long,
+                qbe::Instr::Add(tape.clone(), qbe::Value::Const(tape_bytes)),
+            );
+            startblock.add_instr(qbe::Instr::Store(
+                qbe::Type::Long,
+                qbe::Value::Global(TAPE_START_NAME.to_owned()),
+                tape.clone(),
+            ));
+            startblock.add_instr(qbe::Instr::Store(
+                qbe::Type::Long,
+                qbe::Value::Global(TAPE_END_NAME.to_owned()),
+                qbe::Value::Temporary("tape_end".to_owned()),
+            ));
+            tape
+        };
 
+        // The tape pointer holds the address of the current cell and is reassigned as it
+        // moves, QBE builds the SSA form so it can live in a register for the whole program
         startblock.assign_instr(
-            qbe::Value::Temporary(stack_pointer_name.to_owned()),
+            qbe::Value::Temporary(STACK_POINTER_NAME.to_owned()),
             qbe::Type::Long,
-            qbe::Instr::Alloc4(4),
+            qbe::Instr::Copy(tape),
         );
         self.blocks.add_block(startblock);
 
-        let mut mainbody = qbe::Block {
+        self.blocks.add_block(qbe::Block {
             label: format!("body.{}", self.blocks.idx),
             statements: vec![],
-        };
-
-        mainbody.add_instr(qbe::Instr::Store(
-            qbe::Type::Word,
-            qbe::Value::Temporary(stack_pointer_name.to_owned()),
-            qbe::Value::Const(0),
-        ));
-        self.blocks.add_block(mainbody);
+        });
         self
     }
 
-    pub fn close_prog(self: &mut Self) {
+    pub fn close_prog(&mut self) {
         const RETURN_SUCCESS: u64 = 0;
-        self.blocks
-            .blocks
-            .last_mut()
-            .unwrap()
-            .add_instr(qbe::Instr::Ret(Some(qbe::Value::Const(RETURN_SUCCESS))));
+        self.add_instr(qbe::Instr::Call(FLUSH_NAME.to_owned(), vec![]));
+        self.add_instr(qbe::Instr::Ret(Some(qbe::Value::Const(RETURN_SUCCESS))));
         self.main_func.blocks.append(&mut self.blocks.blocks);
 
         self.program.add_function(self.main_func.clone());
+        add_output_buffer(&mut self.program);
+        if self.checks_bounds && self.tape.mode != TapeMode::GrowBoth {
+            add_out_of_bounds_routine(&mut self.program);
+        }
+        if self.checks_bounds && self.tape.mode != TapeMode::Fixed {
+            add_grow_routine(&mut self.program, self.tape);
+        }
     }
 
-    pub fn token_array_to_qbe_ir(
-        self: &mut Self,
-        compressed_tokens: Vec<CompressedBrainfuckToken>,
-    ) -> &mut Self {
-        let stack_name = String::from("stack");
-        let stack_pointer_name = String::from("stackptr");
-
-        let mut loop_depth = 0;
-        let mut while_loop_tags: Vec<usize> = vec![];
-        const MUL_ROUNDING_VALUE: u64 = 1;
-        let mut index: usize = 0;
-        let mut varsubindex = 0;
-        let mut currblockidx = 1; // start on body.1
-        while index < compressed_tokens.len() {
-            let currtoken = &compressed_tokens[index];
-            let currblock: &mut qbe::Block = &mut self.blocks.blocks[currblockidx];
-            match currtoken.token {
-                BrainfuckToken::Next => {
-                    currblock.assign_instr(
-                        qbe::Value::Temporary(format!(".{}", varsubindex)),
-                        qbe::Type::Word,
-                        qbe::Instr::Load(
-                            qbe::Type::Word,
-                            qbe::Value::Temporary(stack_pointer_name.to_owned()),
-                        ),
-                    );
-                    varsubindex += 1;
-                    currblock.assign_instr(
-                        qbe::Value::Temporary(format!(".{}", varsubindex)),
-                        qbe::Type::Word,
-                        qbe::Instr::Add(
-                            qbe::Value::Temporary(format!(".{}", varsubindex - 1)),
-                            qbe::Value::Const(currtoken.num),
-                        ),
-                    );
-                    varsubindex += 1;
-                    currblock.add_instr(qbe::Instr::Store(
-                        qbe::Type::Word,
-                        qbe::Value::Temporary(stack_pointer_name.to_owned()),
-                        qbe::Value::Temporary(format!(".{}", varsubindex - 1)),
-                    ));
+    pub fn bfir_to_qbe_ir(&mut self, program: &[BfIr]) -> &mut Self {
+        for node in program {
+            match node {
+                BfIr::MovePtr { delta } => self.move_pointer(*delta),
+                BfIr::AddCell { offset, delta } => {
+                    let address = self.cell_address(*offset);
+                    let value = self.load_cell(address.clone());
+                    let sum = self.add_const(self.cell_type(), value, *delta);
+                    self.store_cell(address, sum);
                 }
-                BrainfuckToken::Prev => {
-                    currblock.assign_instr(
-                        qbe::Value::Temporary(format!(".{}", varsubindex)),
-                        qbe::Type::Word,
-                        qbe::Instr::Load(
-                            qbe::Type::Word,
-                            qbe::Value::Temporary(stack_pointer_name.to_owned()),
-                        ),
-                    );
-                    varsubindex += 1;
-                    currblock.assign_instr(
-                        qbe::Value::Temporary(format!(".{}", varsubindex)),
-                        qbe::Type::Word,
-                        qbe::Instr::Sub(
-                            qbe::Value::Temporary(format!(".{}", varsubindex - 1)),
-                            qbe::Value::Const(currtoken.num),
-                        ),
-                    );
-                    varsubindex += 1;
-                    currblock.add_instr(qbe::Instr::Store(
-                        qbe::Type::Word,
-                        qbe::Value::Temporary(stack_pointer_name.to_owned()),
-                        qbe::Value::Temporary(format!(".{}", varsubindex - 1)),
-                    ));
+                BfIr::SetCell { offset, value } => {
+                    let address = self.cell_address(*offset);
+                    let value = self.cell_const(*value);
+                    self.store_cell(address, value);
                 }
-                BrainfuckToken::Add => {
-                    currblock.assign_instr(
-                        qbe::Value::Temporary(format!(".{}", varsubindex)),
-                        qbe::Type::Word,
-                        qbe::Instr::Load(
-                            qbe::Type::Word,
-                            qbe::Value::Temporary(stack_pointer_name.to_owned()),
-                        ),
-                    );
-                    varsubindex += 1;
-                    currblock.assign_instr(
-                        qbe::Value::Temporary(format!(".{}", varsubindex)),
-                        qbe::Type::Long,
-                        qbe::Instr::Ext(
-                            qbe::Type::SingleWord,
-                            qbe::Value::Temporary(format!(".{}", varsubindex - 1)),
-                        ),
-                    );
-                    varsubindex += 1;
-
-                    currblock.assign_instr(
-                        qbe::Value::Temporary(format!(".{}", varsubindex)),
-                        qbe::Type::Long,
-                        qbe::Instr::Mul(
-                            qbe::Value::Temporary(format!(".{}", varsubindex - 1)),
-                            qbe::Value::Const(MUL_ROUNDING_VALUE),
-                        ),
-                    );
-                    varsubindex += 1;
-
-                    let stackcopy = varsubindex;
-                    currblock.assign_instr(
-                        qbe::Value::Temporary(format!(".{}", varsubindex)),
-                        qbe::Type::Long,
-                        qbe::Instr::Add(
-                            qbe::Value::Temporary(stack_name.to_owned()),
-                            qbe::Value::Temporary(format!(".{}", varsubindex - 1)),
-                        ),
-                    );
-                    varsubindex += 1;
-
-                    currblock.assign_instr(
-                        qbe::Value::Temporary(format!(".{}", varsubindex)),
-                        qbe::Type::Word,
-                        qbe::Instr::Load(
-                            qbe::Type::SingleByte,
-                            qbe::Value::Temporary(format!(".{}", varsubindex - 1)),
-                        ),
-                    );
-                    varsubindex += 1;
-
-                    currblock.assign_instr(
-                        qbe::Value::Temporary(format!(".{}", varsubindex)),
-                        qbe::Type::Word,
-                        qbe::Instr::Ext(
-                            qbe::Type::SingleByte,
-                            qbe::Value::Temporary(format!(".{}", varsubindex - 1)),
-                        ),
-                    );
-                    varsubindex += 1;
-
-                    currblock.assign_instr(
-                        qbe::Value::Temporary(format!(".{}", varsubindex)),
-                        qbe::Type::Word,
-                        qbe::Instr::Add(
-                            qbe::Value::Temporary(format!(".{}", varsubindex - 1)),
-                            qbe::Value::Const(currtoken.num),
-                        ),
-                    );
-                    varsubindex += 1;
-                    currblock.add_instr(qbe::Instr::Store(
-                        qbe::Type::Byte,
-                        qbe::Value::Temporary(format!(".{}", stackcopy)),
-                        qbe::Value::Temporary(format!(".{}", varsubindex - 1)),
-                    ));
+                BfIr::MulAdd {
+                    offset,
+                    src,
+                    factor,
+                } => {
+                    let src_address = self.cell_address(*src);
+                    let multiplier = self.load_cell(src_address);
+                    let address = self.cell_address(*offset);
+                    let value = self.load_cell(address.clone());
+                    let ty = self.cell_type();
+                    // Only the cell width survives the store, so any factor works modulo it
+                    let sum = match self.tape.wrap(*factor) {
+                        1 => self.assign(ty, qbe::Instr::Add(value, multiplier)),
+                        factor if factor == self.tape.wrap(-1) => {
+                            self.assign(ty, qbe::Instr::Sub(value, multiplier))
+                        }
+                        _ => {
+                            let factor = self.cell_const(*factor);
+                            let product =
+                                self.assign(ty.clone(), qbe::Instr::Mul(multiplier, factor));
+                            self.assign(ty, qbe::Instr::Add(value, product))
+                        }
+                    };
+                    self.store_cell(address, sum);
                 }
-                BrainfuckToken::Sub => {
-                    currblock.assign_instr(
-                        qbe::Value::Temporary(format!(".{}", varsubindex)),
-                        qbe::Type::Word,
-                        qbe::Instr::Load(
-                            qbe::Type::Word,
-                            qbe::Value::Temporary(stack_pointer_name.to_owned()),
-                        ),
-                    );
-                    varsubindex += 1;
-                    currblock.assign_instr(
-                        qbe::Value::Temporary(format!(".{}", varsubindex)),
-                        qbe::Type::Long,
-                        qbe::Instr::Ext(
-                            qbe::Type::SingleWord,
-                            qbe::Value::Temporary(format!(".{}", varsubindex - 1)),
-                        ),
-                    );
-                    varsubindex += 1;
-
-                    currblock.assign_instr(
-                        qbe::Value::Temporary(format!(".{}", varsubindex)),
-                        qbe::Type::Long,
-                        qbe::Instr::Mul(
-                            qbe::Value::Temporary(format!(".{}", varsubindex - 1)),
-                            qbe::Value::Const(MUL_ROUNDING_VALUE),
-                        ),
-                    );
-                    varsubindex += 1;
-
-                    let stackcopy = varsubindex;
-                    currblock.assign_instr(
-                        qbe::Value::Temporary(format!(".{}", varsubindex)),
-                        qbe::Type::Long,
-                        qbe::Instr::Add(
-                            qbe::Value::Temporary(stack_name.to_owned()),
-                            qbe::Value::Temporary(format!(".{}", varsubindex - 1)),
-                        ),
-                    );
-                    varsubindex += 1;
-
-                    currblock.assign_instr(
-                        qbe::Value::Temporary(format!(".{}", varsubindex)),
-                        qbe::Type::Word,
-                        qbe::Instr::Load(
-                            qbe::Type::SingleByte,
-                            qbe::Value::Temporary(format!(".{}", varsubindex - 1)),
-                        ),
-                    );
-                    varsubindex += 1;
-
-                    currblock.assign_instr(
-                        qbe::Value::Temporary(format!(".{}", varsubindex)),
-                        qbe::Type::Word,
-                        qbe::Instr::Ext(
-                            qbe::Type::SingleByte,
-                            qbe::Value::Temporary(format!(".{}", varsubindex - 1)),
-                        ),
-                    );
-                    varsubindex += 1;
-
-                    currblock.assign_instr(
-                        qbe::Value::Temporary(format!(".{}", varsubindex)),
-                        qbe::Type::Word,
-                        qbe::Instr::Sub(
-                            qbe::Value::Temporary(format!(".{}", varsubindex - 1)),
-                            qbe::Value::Const(currtoken.num),
-                        ),
-                    );
-                    varsubindex += 1;
-                    currblock.add_instr(qbe::Instr::Store(
-                        qbe::Type::Byte,
-                        qbe::Value::Temporary(format!(".{}", stackcopy)),
-                        qbe::Value::Temporary(format!(".{}", varsubindex - 1)),
-                    ));
+                BfIr::Input { offset } => self.input(*offset),
+                BfIr::Output { offset, count } => {
+                    let address = self.cell_address(*offset);
+                    let value = self.load_cell(address);
+                    let mut remaining = *count;
+                    while remaining > 0 {
+                        let chunk = remaining.min(OUTPUT_BUFFER_SIZE);
+                        self.output(value.clone(), chunk);
+                        remaining -= chunk;
+                    }
                 }
-                BrainfuckToken::Input => {
-                    let getchar_place = varsubindex;
-                    currblock.assign_instr(
-                        qbe::Value::Temporary(format!(".{}", varsubindex)),
-                        qbe::Type::Word,
-                        qbe::Instr::Call("getchar".to_owned(), vec![]),
-                    );
-                    varsubindex += 1;
-
-                    currblock.assign_instr(
-                        qbe::Value::Temporary(format!(".{}", varsubindex)),
-                        qbe::Type::Word,
-                        qbe::Instr::Load(
-                            qbe::Type::Word,
-                            qbe::Value::Temporary(stack_pointer_name.to_owned()),
-                        ),
-                    );
-                    varsubindex += 1;
-                    currblock.assign_instr(
-                        qbe::Value::Temporary(format!(".{}", varsubindex)),
+                BfIr::Scan { stride: 1, source } if self.tape.scans_bytes() => {
+                    let tape_end = self.assign(
                         qbe::Type::Long,
-                        qbe::Instr::Ext(
-                            qbe::Type::SingleWord,
-                            qbe::Value::Temporary(format!(".{}", varsubindex - 1)),
+                        qbe::Instr::Add(
+                            qbe::Value::Global(TAPE_NAME.to_owned()),
+                            qbe::Value::Const(self.tape.size as u64),
                         ),
                     );
-                    varsubindex += 1;
-                    currblock.assign_instr(
-                        qbe::Value::Temporary(format!(".{}", varsubindex)),
+                    let remaining = self.assign(
                         qbe::Type::Long,
-                        qbe::Instr::Mul(
-                            qbe::Value::Temporary(format!(".{}", varsubindex - 1)),
-                            qbe::Value::Const(MUL_ROUNDING_VALUE),
+                        qbe::Instr::Sub(
+                            tape_end.clone(),
+                            qbe::Value::Temporary(STACK_POINTER_NAME.to_owned()),
                         ),
                     );
-                    varsubindex += 1;
-
-                    let stackinst = varsubindex;
-                    currblock.assign_instr(
-                        qbe::Value::Temporary(format!(".{}", varsubindex)),
+                    let found = self.assign(
                         qbe::Type::Long,
-                        qbe::Instr::Add(
-                            qbe::Value::Temporary(stack_name.to_owned()),
-                            qbe::Value::Temporary(format!(".{}", varsubindex - 1)),
-                        ),
-                    );
-                    varsubindex += 1;
-
-                    currblock.add_instr(qbe::Instr::Store(
-                        qbe::Type::Byte,
-                        qbe::Value::Temporary(format!(".{}", stackinst)),
-                        qbe::Value::Temporary(format!(".{}", getchar_place)),
-                    ));
-                    varsubindex += 1;
+                        qbe::Instr::Call(
+                            "memchr".to_owned(),
+                            vec![
+                                (
+                                    qbe::Type::Long,
+                                    qbe::Value::Temporary(STACK_POINTER_NAME.to_owned()),
+                                ),
+                                (qbe::Type::Word, qbe::Value::Const(0)),
+                                (qbe::Type::Long, remaining),
+                            ],
+                        ),
+                    );
+                    self.set_scanned_pointer(found, tape_end, *source);
                 }
-                BrainfuckToken::Out => {
-                    currblock.assign_instr(
-                        qbe::Value::Temporary(format!(".{}", varsubindex)),
-                        qbe::Type::Word,
-                        qbe::Instr::Load(
-                            qbe::Type::Word,
-                            qbe::Value::Temporary(stack_pointer_name.to_owned()),
-                        ),
-                    );
-                    varsubindex += 1;
-                    currblock.assign_instr(
-                        qbe::Value::Temporary(format!(".{}", varsubindex)),
+                BfIr::Scan { stride: -1, source }
+                    if self.tape.scans_bytes() && self.target.has_memrchr() =>
+                {
+                    let before = self.assign(
                         qbe::Type::Long,
-                        qbe::Instr::Ext(
-                            qbe::Type::SingleWord,
-                            qbe::Value::Temporary(format!(".{}", varsubindex - 1)),
+                        qbe::Instr::Sub(
+                            qbe::Value::Temporary(STACK_POINTER_NAME.to_owned()),
+                            qbe::Value::Global(TAPE_NAME.to_owned()),
                         ),
                     );
-                    varsubindex += 1;
-                    currblock.assign_instr(
-                        qbe::Value::Temporary(format!(".{}", varsubindex)),
+                    let searched = self.add_const(qbe::Type::Long, before, 1);
+                    let found = self.assign(
                         qbe::Type::Long,
-                        qbe::Instr::Mul(
-                            qbe::Value::Temporary(format!(".{}", varsubindex - 1)),
-                            qbe::Value::Const(MUL_ROUNDING_VALUE),
+                        qbe::Instr::Call(
+                            "memrchr".to_owned(),
+                            vec![
+                                (qbe::Type::Long, qbe::Value::Global(TAPE_NAME.to_owned())),
+                                (qbe::Type::Word, qbe::Value::Const(0)),
+                                (qbe::Type::Long, searched),
+                            ],
                         ),
                     );
-                    varsubindex += 1;
-                    currblock.assign_instr(
-                        qbe::Value::Temporary(format!(".{}", varsubindex)),
+                    let before_tape = self.add_const(
                         qbe::Type::Long,
-                        qbe::Instr::Add(
-                            qbe::Value::Temporary(stack_name.to_owned()),
-                            qbe::Value::Temporary(format!(".{}", varsubindex - 1)),
-                        ),
-                    );
-                    varsubindex += 1;
-                    currblock.assign_instr(
-                        qbe::Value::Temporary(format!(".{}", varsubindex)),
-                        qbe::Type::Word,
-                        qbe::Instr::Load(
-                            qbe::Type::SingleByte,
-                            qbe::Value::Temporary(format!(".{}", varsubindex - 1)),
-                        ),
-                    );
-                    varsubindex += 1;
-                    currblock.assign_instr(
-                        qbe::Value::Temporary(format!(".{}", varsubindex)),
-                        qbe::Type::Word,
-                        qbe::Instr::Ext(
-                            qbe::Type::SingleByte,
-                            qbe::Value::Temporary(format!(".{}", varsubindex - 1)),
-                        ),
+                        qbe::Value::Global(TAPE_NAME.to_owned()),
+                        -1,
                     );
-                    varsubindex += 1;
-
-                    for _ in 0..currtoken.num {
-                        currblock.assign_instr(
-                            qbe::Value::Temporary(format!(".{}", varsubindex)),
-                            qbe::Type::Word,
-                            qbe::Instr::Call(
-                                String::from("putchar"),
-                                vec![(
-                                    qbe::Type::Word,
-                                    qbe::Value::Temporary(format!(".{}", varsubindex - 1)),
-                                )],
-                            ),
-                        );
-                    }
-                    varsubindex += 1;
+                    self.set_scanned_pointer(found, before_tape, *source);
                 }
-                BrainfuckToken::LoopStart => {
-                    let mut condblock = qbe::Block {
-                        label: format!("while_cond.{}", self.blocks.idx),
+                BfIr::Scan { stride, .. } => self.strided_scan(*stride),
+                BfIr::BoundsCheck { offset, source } => self.bounds_check(*offset, *source),
+                BfIr::Write { bytes } => self.write(bytes),
+                BfIr::Loop { body, .. } => {
+                    let loop_idx = self.blocks.idx;
+                    self.blocks.add_block(qbe::Block {
+                        label: format!("while_cond.{}", loop_idx),
                         statements: vec![],
-                    };
-                    self.blocks.idx += 1;
-
-                    condblock.assign_instr(
-                        qbe::Value::Temporary(format!(".{}", varsubindex)),
-                        qbe::Type::Word,
-                        qbe::Instr::Load(
-                            qbe::Type::Word,
-                            qbe::Value::Temporary(stack_pointer_name.to_owned()),
-                        ),
-                    );
-                    varsubindex += 1;
-                    condblock.assign_instr(
-                        qbe::Value::Temporary(format!(".{}", varsubindex)),
-                        qbe::Type::Long,
-                        qbe::Instr::Ext(
-                            qbe::Type::SingleWord,
-                            qbe::Value::Temporary(format!(".{}", varsubindex - 1)),
-                        ),
-                    );
-                    varsubindex += 1;
-                    condblock.assign_instr(
-                        qbe::Value::Temporary(format!(".{}", varsubindex)),
-                        qbe::Type::Long,
-                        qbe::Instr::Mul(
-                            qbe::Value::Temporary(format!(".{}", varsubindex - 1)),
-                            qbe::Value::Const(MUL_ROUNDING_VALUE),
-                        ),
-                    );
-                    varsubindex += 1;
-                    condblock.assign_instr(
-                        qbe::Value::Temporary(format!(".{}", varsubindex)),
-                        qbe::Type::Long,
-                        qbe::Instr::Add(
-                            qbe::Value::Temporary(stack_name.to_owned()),
-                            qbe::Value::Temporary(format!(".{}", varsubindex - 1)),
-                        ),
-                    );
-                    varsubindex += 1;
-                    condblock.assign_instr(
-                        qbe::Value::Temporary(format!(".{}", varsubindex)),
-                        qbe::Type::Word,
-                        qbe::Instr::Load(
-                            qbe::Type::SingleByte,
-                            qbe::Value::Temporary(format!(".{}", varsubindex - 1)),
-                        ),
-                    );
-                    varsubindex += 1;
-                    condblock.assign_instr(
-                        qbe::Value::Temporary(format!(".{}", varsubindex)),
-                        qbe::Type::Word,
-                        qbe::Instr::Ext(
-                            qbe::Type::SingleByte,
-                            qbe::Value::Temporary(format!(".{}", varsubindex - 1)),
-                        ),
-                    );
-                    varsubindex += 1;
-                    const POINTER_MUST_BE_X_AT_WHILE: u64 = 0;
-                    condblock.assign_instr(
-                        qbe::Value::Temporary(format!(".{}", varsubindex)),
-                        qbe::Type::Word,
-                        qbe::Instr::Cmp(
-                            qbe::Type::Word,
-                            qbe::Cmp::Ne,
-                            qbe::Value::Temporary(format!(".{}", varsubindex - 1)),
-                            qbe::Value::Const(POINTER_MUST_BE_X_AT_WHILE),
-                        ),
-                    );
-                    varsubindex += 1;
-                    condblock.add_instr(qbe::Instr::Jnz(
-                        qbe::Value::Temporary(format!(".{}", varsubindex - 1)),
-                        format!("while_body.{}", self.blocks.idx),
-                        format!("while_join.{}", self.blocks.idx + 1), // body
+                    });
+                    let address = self.cell_address(0);
+                    let value = self.cell_is_nonzero(address);
+                    self.add_instr(qbe::Instr::Jnz(
+                        value,
+                        format!("while_body.{}", loop_idx),
+                        format!("while_join.{}", loop_idx),
                     ));
-                    self.blocks.idx += 1; // register join
-                    while_loop_tags.push(self.blocks.idx - 2);
 
-                    self.blocks.blocks.push(condblock);
-                    let newblock = qbe::Block {
-                        label: format!("while_body.{}", self.blocks.idx - 1),
+                    self.blocks.add_block(qbe::Block {
+                        label: format!("while_body.{}", loop_idx),
                         statements: vec![],
-                    };
-                    self.blocks.idx += 1;
-                    self.blocks.blocks.push(newblock);
-                    loop_depth += 1;
-                    currblockidx += 2;
-                }
-                BrainfuckToken::LoopEnd => {
-                    if loop_depth == 0 {
-                        panic!("Unmatched loop end");
-                    }
-                    loop_depth -= 1;
-
-                    currblock.add_instr(qbe::Instr::Jmp(format!(
-                        "while_cond.{}",
-                        (while_loop_tags.last().unwrap())
-                    )));
-                    self.blocks.blocks.push(qbe::Block {
-                        label: format!("while_join.{}", while_loop_tags.last().unwrap() + 2),
+                    });
+                    self.bfir_to_qbe_ir(body);
+                    self.add_instr(qbe::Instr::Jmp(format!("while_cond.{}", loop_idx)));
+
+                    self.blocks.add_block(qbe::Block {
+                        label: format!("while_join.{}", loop_idx),
                         statements: vec![],
                     });
-                    while_loop_tags.pop();
-                    currblockidx += 1;
                 }
-                BrainfuckToken::Invalid => (),
             }
-            index += 1;
         }
         self
     }
+
+    /// Makes sure the cell at `offset` is on the tape, by stopping the program in a fixed tape
+    /// or by growing the tape until it is
+    fn bounds_check(&mut self, offset: i64, source: usize) {
+        self.checks_bounds = true;
+        let check_idx = self.blocks.idx;
+        let address = self.cell_address(offset);
+        let (tape_start, tape_end) = if self.tape.mode == TapeMode::Fixed {
+            let tape = qbe::Value::Global(TAPE_NAME.to_owned());
+            let tape_bytes = self.tape.size as u64 * self.tape.cell_bytes();
+            let tape_end = self.assign(
+                qbe::Type::Long,
+                qbe::Instr::Add(tape.clone(), qbe::Value::Const(tape_bytes)),
+            );
+            (tape, tape_end)
+        } else {
+            (
+                self.assign(
+                    qbe::Type::Long,
+                    qbe::Instr::Load(
+                        qbe::Type::Long,
+                        qbe::Value::Global(TAPE_START_NAME.to_owned()),
+                    ),
+                ),
+                self.assign(
+                    qbe::Type::Long,
+                    qbe::Instr::Load(
+                        qbe::Type::Long,
+                        qbe::Value::Global(TAPE_END_NAME.to_owned()),
+                    ),
+                ),
+            )
+        };
+        let below = self.assign(
+            qbe::Type::Word,
+            qbe::Instr::Cmp(qbe::Type::Long, qbe::Cmp::Slt, address.clone(), tape_start),
+        );
+        let above = self.assign(
+            qbe::Type::Word,
+            qbe::Instr::Cmp(qbe::Type::Long, qbe::Cmp::Sge, address.clone(), tape_end),
+        );
+        let out_of_bounds = format!("out_of_bounds.{}", check_idx);
+        let grow = format!("grow.{}", check_idx);
+        let in_bounds = format!("in_bounds.{}", check_idx);
+        match self.tape.mode {
+            TapeMode::Fixed => {
+                let outside = self.assign(qbe::Type::Word, qbe::Instr::Or(below, above));
+                self.add_instr(qbe::Instr::Jnz(
+                    outside,
+                    out_of_bounds.clone(),
+                    in_bounds.clone(),
+                ));
+            }
+            TapeMode::Grow => {
+                let check_end = format!("check_end.{}", check_idx);
+                self.add_instr(qbe::Instr::Jnz(
+                    below,
+                    out_of_bounds.clone(),
+                    check_end.clone(),
+                ));
+                self.blocks.add_block(qbe::Block {
+                    label: check_end,
+                    statements: vec![],
+                });
+                self.add_instr(qbe::Instr::Jnz(above, grow.clone(), in_bounds.clone()));
+            }
+            TapeMode::GrowBoth => {
+                let outside = self.assign(qbe::Type::Word, qbe::Instr::Or(below, above));
+                self.add_instr(qbe::Instr::Jnz(outside, grow.clone(), in_bounds.clone()));
+            }
+        }
+
+        if self.tape.mode != TapeMode::GrowBoth {
+            self.blocks.add_block(qbe::Block {
+                label: out_of_bounds,
+                statements: vec![],
+            });
+            self.add_instr(qbe::Instr::Call(FLUSH_NAME.to_owned(), vec![]));
+            self.add_instr(qbe::Instr::Call(
+                OUT_OF_BOUNDS_NAME.to_owned(),
+                vec![(qbe::Type::Long, qbe::Value::Const(source as u64))],
+            ));
+            self.add_instr(qbe::Instr::Ret(Some(qbe::Value::Const(
+                OUT_OF_BOUNDS_EXIT_CODE as u64,
+            ))));
+        }
+
+        if self.tape.mode != TapeMode::Fixed {
+            // Growing may move the whole tape, the pointer has to follow it
+            self.blocks.add_block(qbe::Block {
+                label: grow,
+                statements: vec![],
+            });
+            let moved = self.assign(
+                qbe::Type::Long,
+                qbe::Instr::Call(GROW_NAME.to_owned(), vec![(qbe::Type::Long, address)]),
+            );
+            self.set_pointer(qbe::Instr::Add(
+                qbe::Value::Temporary(STACK_POINTER_NAME.to_owned()),
+                moved,
+            ));
+        }
+
+        self.blocks.add_block(qbe::Block {
+            label: in_bounds,
+            statements: vec![],
+        });
+    }
+
+    /// Appends `count` copies of `value` to the output buffer, flushing it first if they do
+    /// not fit
+    fn output(&mut self, value: qbe::Value, count: u64) {
+        let output_idx = self.blocks.idx;
+        let length_global = qbe::Value::Global(OUTPUT_LENGTH_NAME.to_owned());
+        let length = self.assign(
+            qbe::Type::Long,
+            qbe::Instr::Load(qbe::Type::Long, length_global.clone()),
+        );
+        let after = self.add_const(qbe::Type::Long, length, count as i64);
+        let full = self.assign(
+            qbe::Type::Word,
+            qbe::Instr::Cmp(
+                qbe::Type::Long,
+                qbe::Cmp::Sgt,
+                after,
+                qbe::Value::Const(OUTPUT_BUFFER_SIZE),
+            ),
+        );
+        self.add_instr(qbe::Instr::Jnz(
+            full,
+            format!("output_flush.{}", output_idx),
+            format!("output_append.{}", output_idx),
+        ));
+
+        self.blocks.add_block(qbe::Block {
+            label: format!("output_flush.{}", output_idx),
+            statements: vec![],
+        });
+        self.add_instr(qbe::Instr::Call(FLUSH_NAME.to_owned(), vec![]));
+
+        self.blocks.add_block(qbe::Block {
+            label: format!("output_append.{}", output_idx),
+            statements: vec![],
+        });
+        let length = self.assign(
+            qbe::Type::Long,
+            qbe::Instr::Load(qbe::Type::Long, length_global.clone()),
+        );
+        let destination = self.assign(
+            qbe::Type::Long,
+            qbe::Instr::Add(
+                qbe::Value::Global(OUTPUT_BUFFER_NAME.to_owned()),
+                length.clone(),
+            ),
+        );
+        if count == 1 {
+            self.add_instr(qbe::Instr::Store(qbe::Type::Byte, destination, value));
+        } else {
+            self.add_instr(qbe::Instr::Call(
+                "memset".to_owned(),
+                vec![
+                    (qbe::Type::Long, destination),
+                    (qbe::Type::Word, value),
+                    (qbe::Type::Long, qbe::Value::Const(count)),
+                ],
+            ));
+        }
+        let length = self.add_const(qbe::Type::Long, length, count as i64);
+        self.add_instr(qbe::Instr::Store(qbe::Type::Long, length_global, length));
+    }
+
+    /// Writes `bytes` out of a data definition of their own, after anything still buffered
+    fn write(&mut self, bytes: &[u8]) {
+        let name = format!("qbfc_bytes.{}", self.varsubindex);
+        self.varsubindex += 1;
+        self.program.add_data(qbe::DataDef::new(
+            qbe::Linkage::private(),
+            name.clone(),
+            None,
+            vec![(qbe::Type::Byte, qbe::DataItem::Str(escape_bytes(bytes)))],
+        ));
+        self.add_instr(qbe::Instr::Call(FLUSH_NAME.to_owned(), vec![]));
+        self.add_instr(qbe::Instr::Call(
+            WRITE_NAME.to_owned(),
+            vec![
+                (qbe::Type::Long, qbe::Value::Global(name)),
+                (qbe::Type::Long, qbe::Value::Const(bytes.len() as u64)),
+            ],
+        ));
+    }
+
+    fn input(&mut self, offset: i64) {
+        // Whoever is on the other side may be waiting for our prompt
+        self.add_instr(qbe::Instr::Call(FLUSH_NAME.to_owned(), vec![]));
+        let input = self.assign(
+            qbe::Type::Word,
+            qbe::Instr::Call("getchar".to_owned(), vec![]),
+        );
+        // The -1 `getchar` returns on end of input has to stay -1 in 64-bit cells
+        let value = match self.cell_type() {
+            qbe::Type::Long => self.assign(
+                qbe::Type::Long,
+                qbe::Instr::Ext(qbe::Type::SingleWord, input.clone()),
+            ),
+            _ => input.clone(),
+        };
+        let address = self.cell_address(offset);
+        if self.tape.eof == Eof::MinusOne {
+            self.store_cell(address, value);
+            return;
+        }
+
+        let input_idx = self.blocks.idx;
+        let eof = self.assign(
+            qbe::Type::Word,
+            qbe::Instr::Cmp(qbe::Type::Word, qbe::Cmp::Slt, input, qbe::Value::Const(0)),
+        );
+        self.add_instr(qbe::Instr::Jnz(
+            eof,
+            format!("input_eof.{}", input_idx),
+            format!("input_store.{}", input_idx),
+        ));
+
+        self.blocks.add_block(qbe::Block {
+            label: format!("input_eof.{}", input_idx),
+            statements: vec![],
+        });
+        if self.tape.eof == Eof::Zero {
+            self.store_cell(address.clone(), qbe::Value::Const(0));
+        }
+        self.add_instr(qbe::Instr::Jmp(format!("input_join.{}", input_idx)));
+
+        self.blocks.add_block(qbe::Block {
+            label: format!("input_store.{}", input_idx),
+            statements: vec![],
+        });
+        self.store_cell(address, value);
+
+        self.blocks.add_block(qbe::Block {
+            label: format!("input_join.{}", input_idx),
+            statements: vec![],
+        });
+    }
+
+    /// Moves the tape pointer `stride` cells at a time until it lands on a zero cell
+    fn strided_scan(&mut self, stride: i64) {
+        let scan_idx = self.blocks.idx;
+        let pointer = qbe::Value::Temporary(STACK_POINTER_NAME.to_owned());
+        self.blocks.add_block(qbe::Block {
+            label: format!("scan_cond.{}", scan_idx),
+            statements: vec![],
+        });
+        let value = self.cell_is_nonzero(pointer);
+        self.add_instr(qbe::Instr::Jnz(
+            value,
+            format!("scan_body.{}", scan_idx),
+            format!("scan_join.{}", scan_idx),
+        ));
+
+        self.blocks.add_block(qbe::Block {
+            label: format!("scan_body.{}", scan_idx),
+            statements: vec![],
+        });
+        self.move_pointer(stride);
+        self.add_instr(qbe::Instr::Jmp(format!("scan_cond.{}", scan_idx)));
+
+        self.blocks.add_block(qbe::Block {
+            label: format!("scan_join.{}", scan_idx),
+            statements: vec![],
+        });
+    }
+
+    fn move_pointer(&mut self, delta: i64) {
+        let pointer = qbe::Value::Temporary(STACK_POINTER_NAME.to_owned());
+        let step = qbe::Value::Const(delta.unsigned_abs() * self.tape.cell_bytes());
+        self.set_pointer(if delta < 0 {
+            qbe::Instr::Sub(pointer, step)
+        } else {
+            qbe::Instr::Add(pointer, step)
+        });
+    }
+
+    /// Reassigns the tape pointer, QBE builds the SSA form for us
+    /// Moves the pointer to the cell `memchr` or `memrchr` `found`, or to `missed` off the
+    /// tape when they came back with NULL, where the bounds check blames `source`
+    fn set_scanned_pointer(&mut self, found: qbe::Value, missed: qbe::Value, source: usize) {
+        let missing = self.assign(
+            qbe::Type::Long,
+            qbe::Instr::Cmp(
+                qbe::Type::Long,
+                qbe::Cmp::Eq,
+                found.clone(),
+                qbe::Value::Const(0),
+            ),
+        );
+        let correction = self.assign(qbe::Type::Long, qbe::Instr::Mul(missing, missed));
+        self.set_pointer(qbe::Instr::Add(found, correction));
+        self.bounds_check(0, source);
+    }
+
+    fn set_pointer(&mut self, instr: qbe::Instr<'a>) {
+        self.add_assign(
+            qbe::Value::Temporary(STACK_POINTER_NAME.to_owned()),
+            qbe::Type::Long,
+            instr,
+        );
+    }
+
+    fn add_assign(&mut self, temp: qbe::Value, ty: qbe::Type<'a>, instr: qbe::Instr<'a>) {
+        self.blocks
+            .blocks
+            .last_mut()
+            .unwrap()
+            .assign_instr(temp, ty, instr);
+    }
+
+    fn add_instr(&mut self, instr: qbe::Instr<'a>) {
+        self.blocks.blocks.last_mut().unwrap().add_instr(instr);
+    }
+
+    /// Assigns the result of `instr` to a fresh temporary and returns it
+    fn assign(&mut self, ty: qbe::Type<'a>, instr: qbe::Instr<'a>) -> qbe::Value {
+        let temp = qbe::Value::Temporary(format!(".{}", self.varsubindex));
+        self.varsubindex += 1;
+        self.add_assign(temp.clone(), ty, instr);
+        temp
+    }
+
+    /// Adds a possibly negative constant, QBE reads constants as signed so avoid huge unsigned ones
+    fn add_const(&mut self, ty: qbe::Type<'a>, value: qbe::Value, delta: i64) -> qbe::Value {
+        if delta == 0 {
+            value
+        } else if delta < 0 {
+            self.assign(
+                ty,
+                qbe::Instr::Sub(value, qbe::Value::Const(delta.unsigned_abs())),
+            )
+        } else {
+            self.assign(ty, qbe::Instr::Add(value, qbe::Value::Const(delta as u64)))
+        }
+    }
+
+    /// `value` wrapped to the cell width, 64-bit values past i64::MAX are computed as negative
+    /// ones since QBE would saturate them
+    fn cell_const(&mut self, value: i64) -> qbe::Value {
+        match self.tape.wrap(value) {
+            wrapped if wrapped > i64::MAX as u64 => self.assign(
+                qbe::Type::Long,
+                qbe::Instr::Sub(
+                    qbe::Value::Const(0),
+                    qbe::Value::Const(wrapped.wrapping_neg()),
+                ),
+            ),
+            wrapped => qbe::Value::Const(wrapped),
+        }
+    }
+
+    /// Address of the cell `offset` cells away from the tape pointer
+    fn cell_address(&mut self, offset: i64) -> qbe::Value {
+        self.add_const(
+            qbe::Type::Long,
+            qbe::Value::Temporary(STACK_POINTER_NAME.to_owned()),
+            offset * self.tape.cell_bytes() as i64,
+        )
+    }
+
+    /// Type cell values are computed in, 64-bit cells do not fit in a word
+    fn cell_type(&self) -> qbe::Type<'a> {
+        match self.tape.cell_bits {
+            64 => qbe::Type::Long,
+            _ => qbe::Type::Word,
+        }
+    }
+
+    fn load_cell(&mut self, address: qbe::Value) -> qbe::Value {
+        let ty = match self.tape.cell_bits {
+            8 => qbe::Type::SingleByte,
+            16 => qbe::Type::SingleHalfword,
+            32 => qbe::Type::Word,
+            _ => qbe::Type::Long,
+        };
+        self.assign(self.cell_type(), qbe::Instr::Load(ty, address))
+    }
+
+    fn store_cell(&mut self, address: qbe::Value, value: qbe::Value) {
+        let ty = match self.tape.cell_bits {
+            8 => qbe::Type::Byte,
+            16 => qbe::Type::Halfword,
+            32 => qbe::Type::Word,
+            _ => qbe::Type::Long,
+        };
+        self.add_instr(qbe::Instr::Store(ty, address, value));
+    }
+
+    /// Word that is nonzero when the cell at `address` is, `jnz` only looks at the low 32 bits
+    fn cell_is_nonzero(&mut self, address: qbe::Value) -> qbe::Value {
+        let value = self.load_cell(address);
+        match self.cell_type() {
+            qbe::Type::Long => self.assign(
+                qbe::Type::Word,
+                qbe::Instr::Cmp(qbe::Type::Long, qbe::Cmp::Ne, value, qbe::Value::Const(0)),
+            ),
+            _ => value,
+        }
+    }
+}
+
+/// Spells `bytes` out for a QBE string or an `.ascii` directive, which the assembler gets to
+/// read as is, so anything that is not plain printable ASCII becomes an octal escape
+pub fn escape_bytes(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|&byte| match byte {
+            b' '..=b'~' if byte != b'"' && byte != b'\\' => (byte as char).to_string(),
+            _ => format!("\\{:03o}", byte),
+        })
+        .collect()
 }
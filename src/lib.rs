@@ -0,0 +1,250 @@
+//! Brainfuck compiler, from source through BfIr to QBE IL, C, WebAssembly text, assembly or
+//! a linked binary
+//!
+//! ```no_run
+//! let options = qbfc::Options::default();
+//! let tokens = qbfc::tokenize(b"++++++++[>++++++++<-]>+.");
+//! let bfir = qbfc::optimize(&tokens, &options)?;
+//! qbfc::build_binary(&bfir, &options, "hello")?;
+//! # Ok::<(), qbfc::QbfcError>(())
+//! ```
+
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+mod bfir;
+mod c;
+mod diagnostic;
+mod error;
+mod evaluate;
+mod interpreter;
+mod ir;
+mod optimize;
+mod runtime;
+mod tape;
+mod target;
+mod token;
+mod wat;
+mod x86_64;
+use bfir::BfIrLowerable;
+use c::CLowerable;
+use evaluate::ConstantFoldable;
+use interpreter::{Interpreter, InterpreterError};
+use ir::QBEIr;
+use optimize::OptimizableIr;
+use token::{BracketTable, CleanableTokenCollection, CompressableTokenCollection};
+use token::{TokenizableSource, ValidTokenCollection};
+use wat::WatLowerable;
+use x86_64::X86_64Lowerable;
+
+pub use bfir::BfIr;
+pub use diagnostic::Diagnostic;
+pub use error::QbfcError;
+pub use qbe;
+pub use tape::{Eof, Tape, TapeMode, DEFAULT_TAPE_SIZE, OUT_OF_BOUNDS_EXIT_CODE};
+pub use target::Target;
+pub use token::{BrainfuckToken, CompressedBrainfuckToken, Span};
+
+/// What turns the program into assembly for `emit_asm` and `build_binary`
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Backend {
+    Qbe,
+    #[cfg_attr(feature = "cli", value(name = "x86-64"))]
+    X86_64,
+}
+
+/// Everything that changes how a program is compiled
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub tape: Tape,
+    /// Refuse programs with unmatched brackets
+    pub check: bool,
+    /// Run the BfIr optimizations and fold the output before the first read
+    pub optimize: bool,
+    /// Stop programs whose tape pointer leaves a fixed tape
+    pub checked: bool,
+    pub backend: Backend,
+    /// What QBE and cc build for, the host when there is none
+    pub target: Option<Target>,
+    /// QBE command
+    pub qbe: String,
+    /// C compiler that assembles and links, when there is none cc builds for the host and the
+    /// cross compiler of the target for any other target
+    pub cc: Option<String>,
+    /// Flags for the C compiler before the assembly
+    pub cflags: Vec<String>,
+    /// Flags for the C compiler after the assembly, where the linker sees them
+    pub ldflags: Vec<String>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            tape: Tape::default(),
+            check: true,
+            optimize: true,
+            checked: false,
+            backend: Backend::Qbe,
+            target: None,
+            qbe: "qbe".to_owned(),
+            cc: None,
+            cflags: vec![],
+            ldflags: vec![],
+        }
+    }
+}
+
+/// Splits the source into tokens, merging runs and dropping what cancels out
+pub fn tokenize(source: &[u8]) -> Vec<CompressedBrainfuckToken> {
+    source.tokenize().compress().clean()
+}
+
+/// Pairs up the brackets, failing on unmatched ones unless checking is off
+fn brackets(
+    tokens: &[CompressedBrainfuckToken],
+    options: &Options,
+) -> Result<BracketTable, QbfcError> {
+    if options.check {
+        tokens.validate().map_err(QbfcError::UnmatchedBrackets)
+    } else {
+        Ok(tokens.match_brackets())
+    }
+}
+
+/// Lowers the tokens to BfIr, optimized unless the options say otherwise
+pub fn optimize(
+    tokens: &[CompressedBrainfuckToken],
+    options: &Options,
+) -> Result<Vec<BfIr>, QbfcError> {
+    options.tape.check()?;
+    let bfir = tokens.to_bfir(
+        &brackets(tokens, options)?,
+        options.checked || options.tape.mode != TapeMode::Fixed,
+    );
+    if !options.optimize {
+        return Ok(bfir);
+    }
+    Ok(bfir.optimize().fold_constant_output(options.tape))
+}
+
+/// Runs the tokens through the reference interpreter, which behaves like the compiled program
+pub fn interpret(
+    tokens: &[CompressedBrainfuckToken],
+    options: &Options,
+    input: &mut impl Read,
+    output: &mut impl Write,
+) -> Result<(), QbfcError> {
+    options.tape.check()?;
+    let brackets = brackets(tokens, options)?;
+    Interpreter::new(options.tape, options.checked)
+        .run(tokens, &brackets, input, output)
+        .map_err(|err| match err {
+            InterpreterError::PointerOutOfBounds(span) => QbfcError::OutOfBounds(span),
+            InterpreterError::Io(err) => QbfcError::io("failed to run the program", err),
+        })
+}
+
+/// Portable C source of the program
+pub fn emit_c(bfir: &[BfIr], options: &Options) -> Result<String, QbfcError> {
+    options.tape.check()?;
+    Ok(bfir.to_c(options.tape))
+}
+
+/// WebAssembly text module of the program for WASI runtimes
+pub fn emit_wat(bfir: &[BfIr], options: &Options) -> Result<String, QbfcError> {
+    options.tape.check()?;
+    bfir.to_wat(options.tape)
+}
+
+/// QBE IL module of the program, what `emit_asm` hands to QBE
+pub fn lower_to_qbe(bfir: &[BfIr], options: &Options) -> Result<qbe::Module<'static>, QbfcError> {
+    options.tape.check()?;
+    let mut bf_prog = QBEIr::new(options.tape, options.target.unwrap_or_else(Target::host));
+    bf_prog.init_body().bfir_to_qbe_ir(bfir).close_prog();
+    Ok(bf_prog.program)
+}
+
+/// Assembly for the target, from QBE or the x86-64 backend
+pub fn emit_asm(bfir: &[BfIr], options: &Options) -> Result<Vec<u8>, QbfcError> {
+    match options.backend {
+        Backend::Qbe => run_tool(
+            Command::new(&options.qbe)
+                .args(
+                    options
+                        .target
+                        .iter()
+                        .flat_map(|target| ["-t", target.name()]),
+                )
+                .stdout(Stdio::piped()),
+            &options.qbe,
+            lower_to_qbe(bfir, options)?.to_string().as_bytes(),
+        ),
+        Backend::X86_64 if options.target.is_some_and(|t| t != Target::Amd64Sysv) => Err(
+            QbfcError::Usage("the x86-64 backend only builds for amd64_sysv".to_owned()),
+        ),
+        Backend::X86_64 => {
+            options.tape.check()?;
+            Ok(bfir.to_x86_64(options.tape).into_bytes())
+        }
+    }
+}
+
+/// Assembles and links the program into an executable at `output`
+pub fn build_binary(
+    bfir: &[BfIr],
+    options: &Options,
+    output: impl AsRef<Path>,
+) -> Result<(), QbfcError> {
+    let assembly = emit_asm(bfir, options)?;
+    let cc = options.cc.clone().unwrap_or_else(|| {
+        options
+            .target
+            .filter(|&target| target != Target::host())
+            .map_or("cc", Target::cross_compiler)
+            .to_owned()
+    });
+    // The assembly comes in on stdin, `-x none` keeps the linker flags from being read as more
+    // assembly
+    run_tool(
+        Command::new(&cc)
+            .args(&options.cflags)
+            .args(["-x", "assembler", "-", "-x", "none"])
+            .args(&options.ldflags)
+            .arg("-o")
+            .arg(output.as_ref()),
+        &cc,
+        &assembly,
+    )?;
+    Ok(())
+}
+
+/// Runs `command` with `input` on its stdin and returns what it printed, if its stdout is
+/// piped, its stderr goes straight to ours
+fn run_tool(command: &mut Command, tool: &str, input: &[u8]) -> Result<Vec<u8>, QbfcError> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|err| QbfcError::Spawn {
+            tool: tool.to_owned(),
+            err,
+        })?;
+    let mut stdin = child.stdin.take().unwrap();
+    // Feeding the input from another thread keeps a tool that writes before it has read
+    // everything from filling its stdout pipe and waiting on us forever
+    let (written, output) = std::thread::scope(|scope| {
+        let writer = scope.spawn(move || stdin.write_all(input));
+        let output = child.wait_with_output();
+        (writer.join().unwrap(), output)
+    });
+    let output = output.map_err(|err| QbfcError::io(format!("failed to run {}", tool), err))?;
+    if !output.status.success() {
+        return Err(QbfcError::ToolFailed {
+            tool: tool.to_owned(),
+            status: output.status,
+        });
+    }
+    written.map_err(|err| QbfcError::io(format!("failed to write to {}", tool), err))?;
+    Ok(output.stdout)
+}
@@ -1,11 +1,10 @@
+use clap::builder::TypedValueParser;
 use clap::{Parser, ValueEnum};
+use qbfc::*;
+use std::collections::HashMap;
 use std::fs;
-use std::io::Write;
-use std::process::{Command, Stdio};
-mod ir;
-mod token;
-use ir::*;
-use token::*;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
 
 #[derive(ValueEnum, Clone, PartialEq)]
 enum OutputType {
@@ -14,11 +13,20 @@ enum OutputType {
     Asm,
     Ast,
     Debug,
+    Run,
+    Bfir,
+    C,
+    Wat,
 }
 
 #[derive(Parser)]
 struct Cli {
-    file: String,
+    #[clap(
+        required = true,
+        help = "Programs to compile, one after the other unless --out-dir is given, - reads one from stdin except with --type run"
+    )]
+    files: Vec<String>,
+
     #[clap(
         long,
         default_value_t = false,
@@ -26,14 +34,100 @@ struct Cli {
     )]
     no_check: bool,
 
+    #[clap(
+        long,
+        default_value_t = false,
+        help = "Compile the program as written, without optimizations"
+    )]
+    no_optimize: bool,
+
+    #[clap(
+        long,
+        default_value_t = false,
+        help = "Stop compiled programs whose tape pointer leaves the tape"
+    )]
+    checked: bool,
+
+    #[clap(
+        long,
+        default_value_t = DEFAULT_TAPE_SIZE,
+        value_parser = clap::value_parser!(u32).range(1..).map(|size| size as usize),
+        help = "Number of cells on the tape"
+    )]
+    tape_size: usize,
+
+    #[clap(
+        long,
+        default_value = "8",
+        value_parser = clap::builder::PossibleValuesParser::new(["8", "16", "32", "64"])
+            .map(|bits| bits.parse::<u32>().unwrap()),
+        help = "Width of every cell in bits"
+    )]
+    cell_bits: u32,
+
+    #[clap(
+        long,
+        default_value = "fixed",
+        help = "Whether the tape grows when the pointer goes past its end"
+    )]
+    tape: TapeMode,
+
+    #[clap(
+        long,
+        default_value = "minus-one",
+        help = "What reading past the end of the input stores in the cell"
+    )]
+    eof: Eof,
+
     #[clap(
         short,
         long,
         default_value = "binary",
-        help = "Type of output, either AST, ASM, SST or BINARY"
+        help = "Type of output, either AST, ASM, SST, BFIR, C, WAT, BINARY or RUN to interpret the program"
     )]
     r#type: OutputType,
 
+    #[clap(
+        long,
+        default_value = "qbe",
+        help = "Generate assembly through QBE or directly for x86-64"
+    )]
+    backend: Backend,
+
+    #[clap(long, help = "Architecture and ABI to build for, as QBE names them")]
+    target: Option<Target>,
+
+    #[clap(
+        long,
+        env = "QBFC_QBE",
+        default_value = "qbe",
+        help = "QBE command that compiles the program to assembly"
+    )]
+    qbe: String,
+
+    #[clap(
+        long,
+        env = "QBFC_CC",
+        help = "C compiler that assembles and links the program, cc or the cross compiler of a --target other than the host by default"
+    )]
+    cc: Option<String>,
+
+    #[clap(
+        long,
+        default_value = "",
+        allow_hyphen_values = true,
+        help = "Extra flags for the C compiler when it assembles, separated by spaces"
+    )]
+    cflags: String,
+
+    #[clap(
+        long,
+        default_value = "",
+        allow_hyphen_values = true,
+        help = "Extra flags for the C compiler when it links, separated by spaces"
+    )]
+    ldflags: String,
+
     #[clap(
         short,
         long,
@@ -41,114 +135,220 @@ struct Cli {
         help = "Where the output will be put, - for stdout"
     )]
     output: String,
+
+    #[clap(
+        long,
+        conflicts_with = "output",
+        help = "Compile every file on its own into this directory, named after the file"
+    )]
+    out_dir: Option<PathBuf>,
 }
 
-fn main() {
-    let args = Cli::parse();
+impl Cli {
+    fn options(&self) -> Options {
+        Options {
+            tape: Tape {
+                size: self.tape_size,
+                cell_bits: self.cell_bits,
+                eof: self.eof,
+                mode: self.tape,
+            },
+            check: !self.no_check,
+            optimize: !self.no_optimize,
+            checked: self.checked,
+            backend: self.backend,
+            target: self.target,
+            qbe: self.qbe.clone(),
+            cc: self.cc.clone(),
+            cflags: self.cflags.split_whitespace().map(str::to_owned).collect(),
+            ldflags: self.ldflags.split_whitespace().map(str::to_owned).collect(),
+        }
+    }
+}
 
-    let compressed_tokens: Vec<CompressedBrainfuckToken> = fs::read(args.file)
-        .expect("Failed reading file")
-        .iter()
-        .filter_map(|x| {
-            if x.is_valid_token() {
-                Some(Into::<BrainfuckToken>::into(*x))
-            } else {
-                None
-            }
-        })
-        .collect::<Vec<_>>()
-        .compress()
-        .clean();
+/// A program file, or the program on stdin for `-`
+struct Input {
+    name: String,
+    source: Vec<u8>,
+}
 
-    if !args.no_check {
-        if !compressed_tokens.validate() {
-            panic!("Failed due to bracket mismatch");
+impl Input {
+    fn read(path: &str) -> Result<Self, QbfcError> {
+        if path == "-" {
+            let mut source = vec![];
+            io::stdin()
+                .read_to_end(&mut source)
+                .map_err(|err| QbfcError::io("failed to read stdin", err))?;
+            return Ok(Input {
+                name: "<stdin>".to_owned(),
+                source,
+            });
         }
+        let source =
+            fs::read(path).map_err(|err| QbfcError::io(format!("failed to read {}", path), err))?;
+        Ok(Input {
+            name: path.to_owned(),
+            source,
+        })
     }
+}
 
-    let mut bf_prog: QBEIr = QBEIr::new();
-    bf_prog
-        .init_body()
-        .token_array_to_qbe_ir(compressed_tokens)
-        .close_prog();
+/// Name of what `--out-dir` compiles the file at `path` to, the file name with the extension
+/// of the output type
+fn output_name(path: &str, r#type: &OutputType) -> PathBuf {
+    let stem = match path {
+        "-" => "stdin".as_ref(),
+        path => Path::new(path).file_stem().unwrap_or(path.as_ref()),
+    };
+    let extension = match r#type {
+        OutputType::Sst => "ssa",
+        OutputType::Asm => "s",
+        OutputType::Ast => "ast",
+        OutputType::Bfir => "bfir",
+        OutputType::C => "c",
+        OutputType::Wat => "wat",
+        OutputType::Binary | OutputType::Debug | OutputType::Run => "",
+    };
+    Path::new(stem).with_extension(extension)
+}
 
-    match args.r#type {
-        OutputType::Ast => {
-            if args.output == "-" {
-                println!("{:#?}", bf_prog.program);
-                return;
+/// Refuses inputs that would read stdin twice, that `--type run` would also feed the program its
+/// input from or that `--out-dir` would compile to the same file
+fn check_inputs(args: &Cli) -> Result<(), QbfcError> {
+    if args.files.iter().filter(|path| *path == "-").count() > 1 {
+        return Err(QbfcError::Usage(
+            "- can only be given once, stdin has one program".to_owned(),
+        ));
+    }
+    if args.r#type == OutputType::Run && args.files.iter().any(|path| path == "-") {
+        return Err(QbfcError::Usage(
+            "- cannot be run, the program would read its own input from stdin".to_owned(),
+        ));
+    }
+    if args.out_dir.is_some() {
+        let mut outputs = HashMap::new();
+        for path in &args.files {
+            let output = output_name(path, &args.r#type);
+            if let Some(other) = outputs.get(&output) {
+                return Err(QbfcError::Usage(format!(
+                    "{} and {} would both be compiled to {}",
+                    other,
+                    path,
+                    output.display()
+                )));
             }
-            fs::write(args.output, format!("{:#?}", bf_prog.program)).expect("Failed writing file");
+            outputs.insert(output, path);
         }
-        OutputType::Sst => {
-            if args.output == "-" {
-                println!("{}", bf_prog.program);
-                return;
-            }
-            std::fs::write(args.output, format!("{}", bf_prog.program))
-                .expect("Failed writing file");
+    }
+    Ok(())
+}
+
+/// The inputs as one program, each starting on a new line so spans keep their columns
+fn concatenate(inputs: &[Input]) -> Vec<u8> {
+    inputs
+        .iter()
+        .map(|input| input.source.as_slice())
+        .collect::<Vec<_>>()
+        .join(&b'\n')
+}
+
+/// Renders a diagnostic on the concatenated inputs against the input it points into
+fn render(diagnostic: &Diagnostic, inputs: &[Input]) -> String {
+    let mut span = diagnostic.span;
+    for input in inputs {
+        if span.offset < input.source.len() {
+            return Diagnostic::new(diagnostic.message.clone(), span)
+                .render(&input.name, &input.source);
         }
-        OutputType::Asm => {
-            let mut qbeproc = Command::new("qbe")
-                .stdin(Stdio::piped())
-                .stdout(Stdio::piped())
-                .spawn()
-                .expect("Failure finding QBE binary");
-            qbeproc
-                .stdin
-                .as_mut()
-                .unwrap()
-                .write_all(format!("{}", bf_prog.program).as_bytes())
-                .unwrap();
-
-            if args.output == "-" {
-                println!(
-                    "{}",
-                    String::from_utf8(qbeproc.wait_with_output().unwrap().stdout).unwrap()
-                );
-                return;
-            }
-            fs::write(
-                args.output,
-                format!(
-                    "{}",
-                    String::from_utf8(qbeproc.wait_with_output().unwrap().stdout).unwrap()
-                ),
-            )
-            .expect("Failed writing file");
+        span.offset -= input.source.len() + 1;
+        span.line -= input.source.iter().filter(|&&byte| byte == b'\n').count() + 1;
+    }
+    unreachable!("diagnostic past the end of the inputs")
+}
+
+fn main() {
+    let args = Cli::parse();
+    if let Err(err) = check_inputs(&args) {
+        exit_with(err, &[]);
+    }
+
+    let inputs = args
+        .files
+        .iter()
+        .map(|path| Input::read(path))
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap_or_else(|err| exit_with(err, &[]));
+
+    let Some(out_dir) = &args.out_dir else {
+        if let Err(err) = compile(&args, &concatenate(&inputs), Path::new(&args.output)) {
+            exit_with(err, &inputs);
         }
-        OutputType::Binary => {
-            let qbeproc = Command::new("qbe")
-                .stdin(Stdio::piped())
-                .stdout(Stdio::piped())
-                .spawn()
-                .expect("Failure finding QBE binary");
-            qbeproc
-                .stdin
-                .unwrap()
-                .write_all(format!("{}", bf_prog.program).as_bytes())
-                .unwrap();
-            let ccproc = Command::new("cc")
-                .stdout(Stdio::piped())
-                .stdin(Stdio::from(qbeproc.stdout.unwrap()))
-                .args(vec![
-                    "-OFast",
-                    "-x",
-                    "assembler",
-                    "-",
-                    "-v",
-                    "-o",
-                    args.output.as_str(),
-                ])
-                .spawn()
-                .expect("Failure finding any C compiler through cc");
-
-            println!(
-                "{}",
-                String::from_utf8(ccproc.wait_with_output().unwrap().stdout).unwrap()
-            );
-            return;
+        return;
+    };
+    if let Err(err) = fs::create_dir_all(out_dir) {
+        exit_with(
+            QbfcError::io(format!("failed to create {}", out_dir.display()), err),
+            &[],
+        );
+    }
+    for (path, input) in args.files.iter().zip(&inputs) {
+        let output = out_dir.join(output_name(path, &args.r#type));
+        if let Err(err) = compile(&args, &input.source, &output) {
+            exit_with(err, std::slice::from_ref(input));
         }
-        OutputType::Debug => (),
     }
 }
+
+fn exit_with(err: QbfcError, inputs: &[Input]) -> ! {
+    err.report(|diagnostic| render(diagnostic, inputs));
+    std::process::exit(err.exit_code());
+}
+
+fn compile(args: &Cli, source: &[u8], output: &Path) -> Result<(), QbfcError> {
+    let options = args.options();
+    let tokens = tokenize(source);
+
+    if args.r#type == OutputType::Run {
+        let mut output = BufWriter::new(io::stdout().lock());
+        let result = interpret(&tokens, &options, &mut io::stdin().lock(), &mut output);
+        // Whatever the program printed before it failed still goes out
+        output
+            .flush()
+            .map_err(|err| QbfcError::io("failed to write output", err))?;
+        return result;
+    }
+
+    let bfir = optimize(&tokens, &options)?;
+    match args.r#type {
+        OutputType::Bfir => {
+            let listing: String = bfir.iter().map(|node| node.to_string()).collect();
+            write_output(output, listing.as_bytes())
+        }
+        OutputType::C => write_output(output, emit_c(&bfir, &options)?.as_bytes()),
+        OutputType::Wat => write_output(output, emit_wat(&bfir, &options)?.as_bytes()),
+        OutputType::Ast => write_output(
+            output,
+            format!("{:#?}\n", lower_to_qbe(&bfir, &options)?).as_bytes(),
+        ),
+        OutputType::Sst => write_output(
+            output,
+            format!("{}\n", lower_to_qbe(&bfir, &options)?).as_bytes(),
+        ),
+        OutputType::Asm => write_output(output, &emit_asm(&bfir, &options)?),
+        OutputType::Binary => build_binary(&bfir, &options, output),
+        OutputType::Debug | OutputType::Run => Ok(()),
+    }
+}
+
+/// Writes `contents` to the output file, or to stdout for `-`
+fn write_output(output: &Path, contents: &[u8]) -> Result<(), QbfcError> {
+    if output == Path::new("-") {
+        let mut stdout = io::stdout().lock();
+        return stdout
+            .write_all(contents)
+            .and_then(|()| stdout.flush())
+            .map_err(|err| QbfcError::io("failed to write output", err));
+    }
+    fs::write(output, contents)
+        .map_err(|err| QbfcError::io(format!("failed to write {}", output.display()), err))
+}
@@ -0,0 +1,204 @@
+use crate::BfIr;
+use std::collections::BTreeMap;
+
+pub trait OptimizableIr {
+    fn optimize(self) -> Self;
+}
+
+impl OptimizableIr for Vec<BfIr> {
+    fn optimize(self) -> Self {
+        let mut program =
+            fold_offsets(fold_scan_loops(fold_clear_loops(fold_multiply_loops(self))));
+        // Nothing can observe where the pointer ends up once the program is done
+        if let Some(BfIr::MovePtr { .. }) = program.last() {
+            program.pop();
+        }
+        program
+    }
+}
+
+/// Folds pointer movement in straight-line code into the offsets of the cell accesses after
+/// it, so `>+>+<<` becomes two adds at `[p+1]` and `[p+2]` without touching the pointer.
+/// The net movement is applied once before every loop and at the end of the block.
+fn fold_offsets(program: Vec<BfIr>) -> Vec<BfIr> {
+    let mut folded: Vec<BfIr> = Vec::with_capacity(program.len());
+    let mut pending: i64 = 0;
+    for node in program {
+        let node = match node {
+            BfIr::MovePtr { delta } => {
+                pending += delta;
+                continue;
+            }
+            BfIr::AddCell { offset, delta } => BfIr::AddCell {
+                offset: offset + pending,
+                delta,
+            },
+            BfIr::SetCell { offset, value } => BfIr::SetCell {
+                offset: offset + pending,
+                value,
+            },
+            BfIr::MulAdd {
+                offset,
+                src,
+                factor,
+            } => BfIr::MulAdd {
+                offset: offset + pending,
+                src: src + pending,
+                factor,
+            },
+            BfIr::Output { offset, count } => BfIr::Output {
+                offset: offset + pending,
+                count,
+            },
+            BfIr::Input { offset } => BfIr::Input {
+                offset: offset + pending,
+            },
+            BfIr::BoundsCheck { offset, source } => BfIr::BoundsCheck {
+                offset: offset + pending,
+                source,
+            },
+            BfIr::Write { .. } => node,
+            BfIr::Scan { .. } | BfIr::Loop { .. } => {
+                if pending != 0 {
+                    folded.push(BfIr::MovePtr { delta: pending });
+                    pending = 0;
+                }
+                match node {
+                    BfIr::Loop { body, source } => BfIr::Loop {
+                        body: fold_offsets(body),
+                        source,
+                    },
+                    scan => scan,
+                }
+            }
+        };
+        folded.push(node);
+    }
+
+    if pending != 0 {
+        folded.push(BfIr::MovePtr { delta: pending });
+    }
+    folded
+}
+
+/// Turns loops that only move the pointer, like `[>]` or `[<<]`, into scans for a zero cell
+fn fold_scan_loops(program: Vec<BfIr>) -> Vec<BfIr> {
+    program
+        .into_iter()
+        .map(|node| match node {
+            BfIr::Loop { body, source } => match body.as_slice() {
+                [BfIr::MovePtr { delta }] => BfIr::Scan {
+                    stride: *delta,
+                    source,
+                },
+                _ => BfIr::Loop {
+                    body: fold_scan_loops(body),
+                    source,
+                },
+            },
+            other => other,
+        })
+        .collect()
+}
+
+/// Turns balanced loops that only add to cells and change their own cell by one each
+/// iteration, like `[->+>++<<]`, into straight-line multiplications
+fn fold_multiply_loops(program: Vec<BfIr>) -> Vec<BfIr> {
+    let mut folded: Vec<BfIr> = Vec::with_capacity(program.len());
+    for node in program {
+        match node {
+            BfIr::Loop { body, source } => match multiply_loop_factors(&body) {
+                Some(factors) => {
+                    folded.extend(factors.into_iter().map(|(offset, factor)| BfIr::MulAdd {
+                        offset,
+                        src: 0,
+                        factor,
+                    }));
+                    folded.push(BfIr::SetCell {
+                        offset: 0,
+                        value: 0,
+                    });
+                }
+                None => folded.push(BfIr::Loop {
+                    body: fold_multiply_loops(body),
+                    source,
+                }),
+            },
+            other => folded.push(other),
+        }
+    }
+    folded
+}
+
+/// Factor every other cell gets multiplied by when `body` is a multiplication loop
+fn multiply_loop_factors(body: &[BfIr]) -> Option<BTreeMap<i64, i64>> {
+    let mut position: i64 = 0;
+    let mut deltas: BTreeMap<i64, i64> = BTreeMap::new();
+    for node in body {
+        match node {
+            BfIr::MovePtr { delta } => position += delta,
+            BfIr::AddCell { offset, delta } => {
+                let cell = deltas.entry(position + offset).or_insert(0);
+                *cell = cell.wrapping_add(*delta);
+            }
+            _ => return None,
+        }
+    }
+
+    if position != 0 {
+        return None;
+    }
+
+    // Counting down runs the loop `cell` times, counting up runs it `-cell` times
+    let iteration_sign = match deltas.remove(&0) {
+        Some(-1) => 1,
+        Some(1) => -1,
+        _ => return None,
+    };
+    deltas.retain(|_, delta| *delta != 0);
+    deltas
+        .values_mut()
+        .for_each(|delta| *delta = delta.wrapping_mul(iteration_sign));
+    Some(deltas)
+}
+
+/// Turns `[-]`, `[+]` and any loop adding an odd amount to its own cell into a store of zero,
+/// an odd delta always ends on zero because it is coprime with the cell width
+fn fold_clear_loops(program: Vec<BfIr>) -> Vec<BfIr> {
+    let mut folded: Vec<BfIr> = Vec::with_capacity(program.len());
+    for node in program {
+        let node = match node {
+            BfIr::Loop { body, source } => match body.as_slice() {
+                [BfIr::AddCell { offset: 0, delta }] if delta % 2 != 0 => BfIr::SetCell {
+                    offset: 0,
+                    value: 0,
+                },
+                _ => BfIr::Loop {
+                    body: fold_clear_loops(body),
+                    source,
+                },
+            },
+            other => other,
+        };
+
+        // A run of `+`/`-` after a store only changes the stored constant, and a store
+        // makes whatever happened to the cell right before it pointless
+        match (folded.last_mut(), &node) {
+            (
+                Some(BfIr::SetCell { offset, value }),
+                BfIr::AddCell {
+                    offset: add_offset,
+                    delta,
+                },
+            ) if offset == add_offset => *value = value.wrapping_add(*delta),
+            (
+                Some(BfIr::SetCell { offset, .. } | BfIr::AddCell { offset, .. }),
+                BfIr::SetCell {
+                    offset: set_offset, ..
+                },
+            ) if offset == set_offset => *folded.last_mut().unwrap() = node,
+            _ => folded.push(node),
+        }
+    }
+    folded
+}
@@ -0,0 +1,490 @@
+//! Helpers the generated programs call into, emitted into the module next to `main`
+
+use crate::{Tape, TapeMode, OUT_OF_BOUNDS_EXIT_CODE};
+
+/// Globals holding the bounds of a tape that can grow
+pub const TAPE_START_NAME: &str = "qbfc_tape_start";
+pub const TAPE_END_NAME: &str = "qbfc_tape_end";
+pub const GROW_NAME: &str = "qbfc_grow_tape";
+pub const OUT_OF_BOUNDS_NAME: &str = "qbfc_out_of_bounds";
+pub const OUTPUT_BUFFER_NAME: &str = "qbfc_output";
+pub const OUTPUT_LENGTH_NAME: &str = "qbfc_output_length";
+pub const FLUSH_NAME: &str = "qbfc_flush";
+pub const WRITE_NAME: &str = "qbfc_write";
+/// Bytes `.` collects before they get written out
+pub const OUTPUT_BUFFER_SIZE: u64 = 4096;
+const STDOUT_FILENO: u64 = 1;
+const STDERR_FILENO: u64 = 2;
+const OUT_OF_BOUNDS_MESSAGE: &str = "tape pointer out of bounds at source offset ";
+
+/// Adds `$qbfc_grow_tape(l address)`, which reallocates the tape so that `address` lands on
+/// it, zeroes the new cells and returns how far the old cells moved
+pub fn add_grow_routine(program: &mut qbe::Module, tape: Tape) {
+    let mut routine = qbe::Function::new(
+        qbe::Linkage::private(),
+        GROW_NAME,
+        vec![(qbe::Type::Long, qbe::Value::Temporary("address".to_owned()))],
+        Some(qbe::Type::Long),
+    );
+    let temp = |name: &str| qbe::Value::Temporary(name.to_owned());
+    let start_global = qbe::Value::Global(TAPE_START_NAME.to_owned());
+    let end_global = qbe::Value::Global(TAPE_END_NAME.to_owned());
+
+    let start = routine.add_block("start");
+    start.assign_instr(
+        temp("start"),
+        qbe::Type::Long,
+        qbe::Instr::Load(qbe::Type::Long, start_global.clone()),
+    );
+    start.assign_instr(
+        temp("end"),
+        qbe::Type::Long,
+        qbe::Instr::Load(qbe::Type::Long, end_global.clone()),
+    );
+    start.assign_instr(
+        temp("length"),
+        qbe::Type::Long,
+        qbe::Instr::Sub(temp("end"), temp("start")),
+    );
+    start.assign_instr(
+        temp("index"),
+        qbe::Type::Long,
+        qbe::Instr::Sub(temp("address"), temp("start")),
+    );
+    // Only a tape growing both ways can be asked to grow to the left
+    if tape.mode == TapeMode::GrowBoth {
+        start.assign_instr(
+            temp("left"),
+            qbe::Type::Word,
+            qbe::Instr::Cmp(
+                qbe::Type::Long,
+                qbe::Cmp::Slt,
+                temp("index"),
+                qbe::Value::Const(0),
+            ),
+        );
+        start.add_instr(qbe::Instr::Jnz(
+            temp("left"),
+            "grow_left".to_owned(),
+            "grow_right".to_owned(),
+        ));
+    }
+
+    // Growing to the right at least doubles the tape, keeping the cells where they are
+    let grow_right = routine.add_block("grow_right");
+    grow_right.assign_instr(
+        temp("needed"),
+        qbe::Type::Long,
+        qbe::Instr::Add(temp("index"), qbe::Value::Const(tape.cell_bytes())),
+    );
+    grow_right.assign_instr(
+        temp("new_length"),
+        qbe::Type::Long,
+        qbe::Instr::Mul(temp("length"), qbe::Value::Const(2)),
+    );
+    grow_right.assign_instr(
+        temp("short"),
+        qbe::Type::Word,
+        qbe::Instr::Cmp(
+            qbe::Type::Long,
+            qbe::Cmp::Slt,
+            temp("new_length"),
+            temp("needed"),
+        ),
+    );
+    grow_right.add_instr(qbe::Instr::Jnz(
+        temp("short"),
+        "fit_right".to_owned(),
+        "resize_right".to_owned(),
+    ));
+    let fit_right = routine.add_block("fit_right");
+    fit_right.assign_instr(
+        temp("new_length"),
+        qbe::Type::Long,
+        qbe::Instr::Copy(temp("needed")),
+    );
+    let resize_right = routine.add_block("resize_right");
+    resize_right.assign_instr(
+        temp("tape"),
+        qbe::Type::Long,
+        qbe::Instr::Call(
+            "realloc".to_owned(),
+            vec![
+                (qbe::Type::Long, temp("start")),
+                (qbe::Type::Long, temp("new_length")),
+            ],
+        ),
+    );
+    resize_right.assign_instr(
+        temp("fresh"),
+        qbe::Type::Long,
+        qbe::Instr::Add(temp("tape"), temp("length")),
+    );
+    resize_right.assign_instr(
+        temp("added"),
+        qbe::Type::Long,
+        qbe::Instr::Sub(temp("new_length"), temp("length")),
+    );
+    resize_right.add_instr(qbe::Instr::Call(
+        "memset".to_owned(),
+        vec![
+            (qbe::Type::Long, temp("fresh")),
+            (qbe::Type::Word, qbe::Value::Const(0)),
+            (qbe::Type::Long, temp("added")),
+        ],
+    ));
+    resize_right.assign_instr(
+        temp("kept"),
+        qbe::Type::Long,
+        qbe::Instr::Copy(temp("tape")),
+    );
+    resize_right.add_instr(qbe::Instr::Jmp("update".to_owned()));
+
+    // Growing to the left at least doubles the tape too, shifting the cells to the right
+    if tape.mode == TapeMode::GrowBoth {
+        let grow_left = routine.add_block("grow_left");
+        grow_left.assign_instr(
+            temp("shift"),
+            qbe::Type::Long,
+            qbe::Instr::Sub(qbe::Value::Const(0), temp("index")),
+        );
+        grow_left.assign_instr(
+            temp("short"),
+            qbe::Type::Word,
+            qbe::Instr::Cmp(
+                qbe::Type::Long,
+                qbe::Cmp::Slt,
+                temp("shift"),
+                temp("length"),
+            ),
+        );
+        grow_left.add_instr(qbe::Instr::Jnz(
+            temp("short"),
+            "fit_left".to_owned(),
+            "resize_left".to_owned(),
+        ));
+        let fit_left = routine.add_block("fit_left");
+        fit_left.assign_instr(
+            temp("shift"),
+            qbe::Type::Long,
+            qbe::Instr::Copy(temp("length")),
+        );
+        let resize_left = routine.add_block("resize_left");
+        resize_left.assign_instr(
+            temp("new_length"),
+            qbe::Type::Long,
+            qbe::Instr::Add(temp("length"), temp("shift")),
+        );
+        resize_left.assign_instr(
+            temp("tape"),
+            qbe::Type::Long,
+            qbe::Instr::Call(
+                "realloc".to_owned(),
+                vec![
+                    (qbe::Type::Long, temp("start")),
+                    (qbe::Type::Long, temp("new_length")),
+                ],
+            ),
+        );
+        resize_left.assign_instr(
+            temp("kept"),
+            qbe::Type::Long,
+            qbe::Instr::Add(temp("tape"), temp("shift")),
+        );
+        resize_left.add_instr(qbe::Instr::Call(
+            "memmove".to_owned(),
+            vec![
+                (qbe::Type::Long, temp("kept")),
+                (qbe::Type::Long, temp("tape")),
+                (qbe::Type::Long, temp("length")),
+            ],
+        ));
+        resize_left.add_instr(qbe::Instr::Call(
+            "memset".to_owned(),
+            vec![
+                (qbe::Type::Long, temp("tape")),
+                (qbe::Type::Word, qbe::Value::Const(0)),
+                (qbe::Type::Long, temp("shift")),
+            ],
+        ));
+    }
+
+    let update = routine.add_block("update");
+    update.add_instr(qbe::Instr::Store(
+        qbe::Type::Long,
+        start_global,
+        temp("tape"),
+    ));
+    update.assign_instr(
+        temp("new_end"),
+        qbe::Type::Long,
+        qbe::Instr::Add(temp("tape"), temp("new_length")),
+    );
+    update.add_instr(qbe::Instr::Store(
+        qbe::Type::Long,
+        end_global,
+        temp("new_end"),
+    ));
+    update.assign_instr(
+        temp("moved"),
+        qbe::Type::Long,
+        qbe::Instr::Sub(temp("kept"), temp("start")),
+    );
+    update.add_instr(qbe::Instr::Ret(Some(temp("moved"))));
+
+    program.add_function(routine);
+}
+
+/// Adds `$qbfc_out_of_bounds(l offset)`, which reports the source offset of the command
+/// that moved the pointer off the tape on stderr and exits
+pub fn add_out_of_bounds_routine(program: &mut qbe::Module) {
+    program.add_data(qbe::DataDef::new(
+        qbe::Linkage::private(),
+        format!("{}_message", OUT_OF_BOUNDS_NAME),
+        None,
+        vec![(
+            qbe::Type::Byte,
+            qbe::DataItem::Str(OUT_OF_BOUNDS_MESSAGE.to_owned()),
+        )],
+    ));
+
+    let mut routine = qbe::Function::new(
+        qbe::Linkage::private(),
+        OUT_OF_BOUNDS_NAME,
+        vec![(qbe::Type::Long, qbe::Value::Temporary("offset".to_owned()))],
+        None,
+    );
+    let temp = |name: &str| qbe::Value::Temporary(name.to_owned());
+
+    // The digits are written right to left in front of a newline at the end of the buffer
+    let start = routine.add_block("start");
+    start.assign_instr(temp("buffer"), qbe::Type::Long, qbe::Instr::Alloc8(24));
+    start.assign_instr(
+        temp("end"),
+        qbe::Type::Long,
+        qbe::Instr::Add(temp("buffer"), qbe::Value::Const(24)),
+    );
+    start.assign_instr(
+        temp("digits"),
+        qbe::Type::Long,
+        qbe::Instr::Sub(temp("end"), qbe::Value::Const(1)),
+    );
+    start.add_instr(qbe::Instr::Store(
+        qbe::Type::Byte,
+        temp("digits"),
+        qbe::Value::Const(b'\n' as u64),
+    ));
+    start.assign_instr(
+        temp("rest"),
+        qbe::Type::Long,
+        qbe::Instr::Copy(temp("offset")),
+    );
+
+    let digit = routine.add_block("digit");
+    digit.assign_instr(
+        temp("digits"),
+        qbe::Type::Long,
+        qbe::Instr::Sub(temp("digits"), qbe::Value::Const(1)),
+    );
+    digit.assign_instr(
+        temp("remainder"),
+        qbe::Type::Long,
+        qbe::Instr::Rem(temp("rest"), qbe::Value::Const(10)),
+    );
+    digit.assign_instr(
+        temp("char"),
+        qbe::Type::Long,
+        qbe::Instr::Add(temp("remainder"), qbe::Value::Const(b'0' as u64)),
+    );
+    digit.add_instr(qbe::Instr::Store(
+        qbe::Type::Byte,
+        temp("digits"),
+        temp("char"),
+    ));
+    digit.assign_instr(
+        temp("rest"),
+        qbe::Type::Long,
+        qbe::Instr::Div(temp("rest"), qbe::Value::Const(10)),
+    );
+    digit.assign_instr(
+        temp("more"),
+        qbe::Type::Word,
+        qbe::Instr::Cmp(
+            qbe::Type::Long,
+            qbe::Cmp::Ne,
+            temp("rest"),
+            qbe::Value::Const(0),
+        ),
+    );
+    digit.add_instr(qbe::Instr::Jnz(
+        temp("more"),
+        "digit".to_owned(),
+        "report".to_owned(),
+    ));
+
+    let report = routine.add_block("report");
+    report.add_instr(qbe::Instr::Call(
+        "write".to_owned(),
+        vec![
+            (qbe::Type::Word, qbe::Value::Const(STDERR_FILENO)),
+            (
+                qbe::Type::Long,
+                qbe::Value::Global(format!("{}_message", OUT_OF_BOUNDS_NAME)),
+            ),
+            (
+                qbe::Type::Long,
+                qbe::Value::Const(OUT_OF_BOUNDS_MESSAGE.len() as u64),
+            ),
+        ],
+    ));
+    report.assign_instr(
+        temp("length"),
+        qbe::Type::Long,
+        qbe::Instr::Sub(temp("end"), temp("digits")),
+    );
+    report.add_instr(qbe::Instr::Call(
+        "write".to_owned(),
+        vec![
+            (qbe::Type::Word, qbe::Value::Const(STDERR_FILENO)),
+            (qbe::Type::Long, temp("digits")),
+            (qbe::Type::Long, temp("length")),
+        ],
+    ));
+    report.add_instr(qbe::Instr::Call(
+        "exit".to_owned(),
+        vec![(
+            qbe::Type::Word,
+            qbe::Value::Const(OUT_OF_BOUNDS_EXIT_CODE as u64),
+        )],
+    ));
+    report.add_instr(qbe::Instr::Ret(None));
+
+    program.add_function(routine);
+}
+
+/// Adds the output buffer, `$qbfc_flush()`, which writes out whatever the buffer holds, and
+/// `$qbfc_write(l from, l length)`, which writes out `length` bytes starting at `from`
+pub fn add_output_buffer(program: &mut qbe::Module) {
+    program.add_data(qbe::DataDef::new(
+        qbe::Linkage::private(),
+        OUTPUT_BUFFER_NAME,
+        None,
+        vec![(qbe::Type::Byte, qbe::DataItem::Zero(OUTPUT_BUFFER_SIZE))],
+    ));
+    program.add_data(qbe::DataDef::new(
+        qbe::Linkage::private(),
+        OUTPUT_LENGTH_NAME,
+        None,
+        vec![(qbe::Type::Long, qbe::DataItem::Const(0))],
+    ));
+    let temp = |name: &str| qbe::Value::Temporary(name.to_owned());
+
+    let mut flush = qbe::Function::new(qbe::Linkage::private(), FLUSH_NAME, vec![], None);
+    let length_global = qbe::Value::Global(OUTPUT_LENGTH_NAME.to_owned());
+    let start = flush.add_block("start");
+    start.assign_instr(
+        temp("length"),
+        qbe::Type::Long,
+        qbe::Instr::Load(qbe::Type::Long, length_global.clone()),
+    );
+    start.add_instr(qbe::Instr::Store(
+        qbe::Type::Long,
+        length_global,
+        qbe::Value::Const(0),
+    ));
+    start.add_instr(qbe::Instr::Call(
+        WRITE_NAME.to_owned(),
+        vec![
+            (
+                qbe::Type::Long,
+                qbe::Value::Global(OUTPUT_BUFFER_NAME.to_owned()),
+            ),
+            (qbe::Type::Long, temp("length")),
+        ],
+    ));
+    start.add_instr(qbe::Instr::Ret(None));
+    program.add_function(flush);
+
+    let mut routine = qbe::Function::new(
+        qbe::Linkage::private(),
+        WRITE_NAME,
+        vec![
+            (qbe::Type::Long, temp("from")),
+            (qbe::Type::Long, temp("length")),
+        ],
+        None,
+    );
+    let start = routine.add_block("start");
+    start.assign_instr(temp("at"), qbe::Type::Long, qbe::Instr::Copy(temp("from")));
+    start.assign_instr(
+        temp("left"),
+        qbe::Type::Long,
+        qbe::Instr::Copy(temp("length")),
+    );
+
+    // `write` can take less than it was given when stdout is a pipe, keep going until it is
+    // all out or writing fails
+    let write = routine.add_block("write");
+    write.assign_instr(
+        temp("pending"),
+        qbe::Type::Word,
+        qbe::Instr::Cmp(
+            qbe::Type::Long,
+            qbe::Cmp::Sgt,
+            temp("left"),
+            qbe::Value::Const(0),
+        ),
+    );
+    write.add_instr(qbe::Instr::Jnz(
+        temp("pending"),
+        "write_some".to_owned(),
+        "done".to_owned(),
+    ));
+
+    let write_some = routine.add_block("write_some");
+    write_some.assign_instr(
+        temp("written"),
+        qbe::Type::Long,
+        qbe::Instr::Call(
+            "write".to_owned(),
+            vec![
+                (qbe::Type::Word, qbe::Value::Const(STDOUT_FILENO)),
+                (qbe::Type::Long, temp("at")),
+                (qbe::Type::Long, temp("left")),
+            ],
+        ),
+    );
+    write_some.assign_instr(
+        temp("failed"),
+        qbe::Type::Word,
+        qbe::Instr::Cmp(
+            qbe::Type::Long,
+            qbe::Cmp::Sle,
+            temp("written"),
+            qbe::Value::Const(0),
+        ),
+    );
+    write_some.add_instr(qbe::Instr::Jnz(
+        temp("failed"),
+        "done".to_owned(),
+        "advance".to_owned(),
+    ));
+
+    let advance = routine.add_block("advance");
+    advance.assign_instr(
+        temp("at"),
+        qbe::Type::Long,
+        qbe::Instr::Add(temp("at"), temp("written")),
+    );
+    advance.assign_instr(
+        temp("left"),
+        qbe::Type::Long,
+        qbe::Instr::Sub(temp("left"), temp("written")),
+    );
+    advance.add_instr(qbe::Instr::Jmp("write".to_owned()));
+
+    let done = routine.add_block("done");
+    done.add_instr(qbe::Instr::Ret(None));
+
+    program.add_function(routine);
+}
@@ -0,0 +1,99 @@
+use crate::QbfcError;
+
+/// Number of cells on the tape unless `--tape-size` says otherwise
+pub const DEFAULT_TAPE_SIZE: usize = 30000;
+
+/// Exit status of a program whose tape pointer left the tape
+pub const OUT_OF_BOUNDS_EXIT_CODE: i32 = 3;
+
+/// What `,` does to the cell once the input is exhausted
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Eof {
+    /// Leaves the cell as it was
+    Unchanged,
+    /// Stores 0
+    Zero,
+    /// Stores -1, what `getchar` returns
+    MinusOne,
+}
+
+/// Whether the tape can grow past the cells it starts with
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum TapeMode {
+    /// Going past either end is an error
+    Fixed,
+    /// The tape grows when the pointer passes its right end
+    Grow,
+    /// The tape grows in both directions
+    GrowBoth,
+}
+
+/// Shape of the tape and how input lands on it, shared by the interpreter and the QBE backend
+#[derive(Debug, Clone, Copy)]
+pub struct Tape {
+    /// Number of cells, or the number it starts with when it can grow
+    pub size: usize,
+    /// Width of every cell, one of 8, 16, 32 or 64
+    pub cell_bits: u32,
+    /// What `,` does once the input is exhausted
+    pub eof: Eof,
+    pub mode: TapeMode,
+}
+
+impl Default for Tape {
+    fn default() -> Self {
+        Tape {
+            size: DEFAULT_TAPE_SIZE,
+            cell_bits: 8,
+            eof: Eof::MinusOne,
+            mode: TapeMode::Fixed,
+        }
+    }
+}
+
+impl Tape {
+    /// Refuses a tape no backend can build, one without cells or with a width other than 8, 16,
+    /// 32 or 64 bits
+    pub fn check(&self) -> Result<(), QbfcError> {
+        if self.size == 0 {
+            return Err(QbfcError::Usage(
+                "the tape needs at least one cell".to_owned(),
+            ));
+        }
+        if ![8, 16, 32, 64].contains(&self.cell_bits) {
+            return Err(QbfcError::Usage(format!(
+                "cells are 8, 16, 32 or 64 bits wide, not {}",
+                self.cell_bits
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn cell_bytes(&self) -> u64 {
+        u64::from(self.cell_bits / 8)
+    }
+
+    /// Whether a scan can look for the zero cell with `memchr`, which needs byte cells and a
+    /// tape whose length is known when compiling
+    pub fn scans_bytes(&self) -> bool {
+        self.cell_bits == 8 && self.mode == TapeMode::Fixed
+    }
+
+    /// Cells a tape of `length` cells grows by so that the cell at `index` lands on it. It at
+    /// least doubles, growing to the right keeps the cells where they are and growing to the
+    /// left shifts them right by the returned amount. Every backend's grow routine does this.
+    pub fn growth(length: u64, index: i64) -> u64 {
+        if index < 0 {
+            index.unsigned_abs().max(length)
+        } else {
+            (index as u64 + 1).max(2 * length) - length
+        }
+    }
+
+    /// Truncates `value` to the cell width, the same way storing it into a cell does
+    pub fn wrap(&self, value: i64) -> u64 {
+        value as u64 & (u64::MAX >> (64 - self.cell_bits))
+    }
+}
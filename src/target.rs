@@ -0,0 +1,59 @@
+/// Architecture and ABI the QBE backend builds for, named the way `qbe -t` names them
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Target {
+    #[cfg_attr(feature = "cli", value(name = "amd64_sysv"))]
+    Amd64Sysv,
+    #[cfg_attr(feature = "cli", value(name = "amd64_apple"))]
+    Amd64Apple,
+    #[cfg_attr(feature = "cli", value(name = "arm64"))]
+    Arm64,
+    #[cfg_attr(feature = "cli", value(name = "arm64_apple"))]
+    Arm64Apple,
+    #[cfg_attr(feature = "cli", value(name = "rv64"))]
+    Rv64,
+}
+
+impl Target {
+    /// The target qbfc itself runs on, what QBE and cc build for when no target is given
+    pub fn host() -> Self {
+        if cfg!(all(target_os = "macos", target_arch = "aarch64")) {
+            Target::Arm64Apple
+        } else if cfg!(target_os = "macos") {
+            Target::Amd64Apple
+        } else if cfg!(target_arch = "aarch64") {
+            Target::Arm64
+        } else if cfg!(target_arch = "riscv64") {
+            Target::Rv64
+        } else {
+            Target::Amd64Sysv
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Target::Amd64Sysv => "amd64_sysv",
+            Target::Amd64Apple => "amd64_apple",
+            Target::Arm64 => "arm64",
+            Target::Arm64Apple => "arm64_apple",
+            Target::Rv64 => "rv64",
+        }
+    }
+
+    /// Compiler that assembles and links for the target from a Linux box, the Debian cross
+    /// toolchains and osxcross for the Apple targets
+    pub fn cross_compiler(self) -> &'static str {
+        match self {
+            Target::Amd64Sysv => "x86_64-linux-gnu-gcc",
+            Target::Amd64Apple => "o64-clang",
+            Target::Arm64 => "aarch64-linux-gnu-gcc",
+            Target::Arm64Apple => "oa64-clang",
+            Target::Rv64 => "riscv64-linux-gnu-gcc",
+        }
+    }
+
+    /// Whether the C library has `memrchr`, a GNU extension the Apple one lacks
+    pub fn has_memrchr(self) -> bool {
+        !matches!(self, Target::Amd64Apple | Target::Arm64Apple)
+    }
+}
@@ -1,3 +1,5 @@
+use crate::diagnostic::Diagnostic;
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum BrainfuckToken {
     Next,
@@ -11,14 +13,30 @@ pub enum BrainfuckToken {
     Invalid,
 }
 
+/// Position of a token in the original source, lines and columns start at 1
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Span {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct SpannedBrainfuckToken {
+    pub token: BrainfuckToken,
+    pub span: Span,
+}
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct CompressedBrainfuckToken {
     pub token: BrainfuckToken,
     pub num: u64,
+    /// Span of the first token of the run
+    pub span: Span,
 }
 
 impl BrainfuckToken {
-    pub fn to_opposite(self: Self) -> Option<BrainfuckToken> {
+    pub fn to_opposite(self) -> Option<BrainfuckToken> {
         match self {
             BrainfuckToken::Next => Some(Self::Prev),
             BrainfuckToken::Prev => Some(Self::Next),
@@ -31,75 +49,146 @@ impl BrainfuckToken {
             _ => None,
         }
     }
+
+    /// Whether two adjacent runs of this token and its opposite cancel out
+    pub fn is_cancellable(self) -> bool {
+        matches!(
+            self,
+            BrainfuckToken::Next | BrainfuckToken::Prev | BrainfuckToken::Add | BrainfuckToken::Sub
+        )
+    }
 }
 
-pub trait ValidTokenCollection {
-    fn validate(self: &Self) -> bool;
+pub trait TokenizableSource {
+    fn tokenize(&self) -> Vec<SpannedBrainfuckToken>;
 }
 
-impl ValidTokenCollection for Vec<CompressedBrainfuckToken> {
-    fn validate(self: &Self) -> bool {
-        let mut index: usize = 0;
-        while index < self.len() {
-            if self[index].token != BrainfuckToken::LoopStart {
-                index += 1;
-                continue;
+impl TokenizableSource for [u8] {
+    fn tokenize(&self) -> Vec<SpannedBrainfuckToken> {
+        let mut tokens: Vec<SpannedBrainfuckToken> = Vec::new();
+        let mut line: usize = 1;
+        let mut column: usize = 1;
+        for (offset, byte) in self.iter().enumerate() {
+            if byte.is_valid_token() {
+                tokens.push(SpannedBrainfuckToken {
+                    token: (*byte).into(),
+                    span: Span {
+                        offset,
+                        line,
+                        column,
+                    },
+                });
             }
 
-            let mut balance = 0;
-            let mut subindex: usize = index;
-            while subindex < self.len() {
-                match self[subindex].token {
-                    BrainfuckToken::LoopStart => balance += 1,
-                    BrainfuckToken::LoopEnd => balance -= 1,
-                    _ => (),
-                }
+            if *byte == b'\n' {
+                line += 1;
+                column = 1;
+            } else {
+                column += 1;
+            }
+        }
+        tokens
+    }
+}
 
-                if balance == 0 {
-                    break;
-                }
+/// Partner of every matched bracket, indexed by token position
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct BracketTable {
+    partners: Vec<Option<usize>>,
+}
 
-                subindex += 1;
-            }
+impl BracketTable {
+    /// Index of the bracket matching the one at `index`, if there is one
+    pub fn partner(&self, index: usize) -> Option<usize> {
+        self.partners.get(index).copied().flatten()
+    }
+}
 
-            if balance != 0 {
-                return false;
-            }
+pub trait ValidTokenCollection {
+    /// Pairs up brackets, leaving unmatched ones without a partner
+    fn match_brackets(&self) -> BracketTable;
+    fn validate(&self) -> Result<BracketTable, Vec<Diagnostic>>;
+}
+
+impl ValidTokenCollection for [CompressedBrainfuckToken] {
+    fn match_brackets(&self) -> BracketTable {
+        pair_brackets(self).0
+    }
 
-            index += 1;
+    fn validate(&self) -> Result<BracketTable, Vec<Diagnostic>> {
+        let (table, unmatched) = pair_brackets(self);
+        if unmatched.is_empty() {
+            return Ok(table);
+        }
+
+        Err(unmatched
+            .into_iter()
+            .map(|index| {
+                let message = match self[index].token {
+                    BrainfuckToken::LoopStart => "unmatched `[`, this loop is never closed",
+                    _ => "unmatched `]`, there is no open loop to close",
+                };
+                Diagnostic::new(message, self[index].span)
+            })
+            .collect())
+    }
+}
+
+/// Single pass over the tokens, returns the table and the sorted indices of unmatched brackets
+fn pair_brackets(tokens: &[CompressedBrainfuckToken]) -> (BracketTable, Vec<usize>) {
+    let mut partners: Vec<Option<usize>> = vec![None; tokens.len()];
+    let mut open_loops: Vec<usize> = Vec::new();
+    let mut unmatched: Vec<usize> = Vec::new();
+    for (index, token) in tokens.iter().enumerate() {
+        match token.token {
+            BrainfuckToken::LoopStart => open_loops.push(index),
+            BrainfuckToken::LoopEnd => match open_loops.pop() {
+                Some(open) => {
+                    partners[open] = Some(index);
+                    partners[index] = Some(open);
+                }
+                None => unmatched.push(index),
+            },
+            _ => (),
         }
-        true
     }
+
+    // Loops still open at the end of the program all come after any unmatched `]`
+    unmatched.append(&mut open_loops);
+    (BracketTable { partners }, unmatched)
 }
 
 pub trait CompressableTokenCollection {
-    fn compress(self: Self) -> Vec<CompressedBrainfuckToken>;
+    fn compress(self) -> Vec<CompressedBrainfuckToken>;
 }
 
 pub trait BfToken {
-    fn is_valid_token(self: Self) -> bool;
+    fn is_valid_token(&self) -> bool;
 }
 
 pub trait CleanableTokenCollection {
-    fn clean(self: Self) -> Self;
+    fn clean(self) -> Self;
 }
 
 impl CleanableTokenCollection for Vec<CompressedBrainfuckToken> {
-    fn clean(self: Self) -> Self {
+    fn clean(self) -> Self {
         let mut cleaned = self;
         let mut idx: usize = 0;
-        while idx < cleaned.len() - 1 {
-            if !cleaned[idx + 1].token.to_opposite().is_none()
-                && cleaned[idx].token == cleaned[idx + 1].token.to_opposite().unwrap()
+        while idx + 1 < cleaned.len() {
+            if cleaned[idx].token.is_cancellable()
+                && cleaned[idx + 1].token.to_opposite() == Some(cleaned[idx].token)
             {
-                if cleaned[idx].num - cleaned[idx + 1].num == 0 {
+                let (lhs, rhs) = (cleaned[idx].num, cleaned[idx + 1].num);
+                if lhs == rhs {
                     cleaned.remove(idx);
                     cleaned.remove(idx);
-                } else if cleaned[idx].num - cleaned[idx + 1].num > 0 {
-                    cleaned[idx].num = cleaned[idx].num - cleaned[idx + 1].num;
+                    // The runs around the removed pair may now cancel each other
+                    idx = idx.saturating_sub(1);
+                } else if lhs > rhs {
+                    cleaned[idx].num = lhs - rhs;
                     cleaned.remove(idx + 1);
-                } else if cleaned[idx + 1].num - cleaned[idx].num > 0 {
-                    cleaned[idx + 1].num = cleaned[idx + 1].num - cleaned[idx].num;
+                } else {
+                    cleaned[idx + 1].num = rhs - lhs;
                     cleaned.remove(idx);
                 }
                 continue;
@@ -110,20 +199,18 @@ impl CleanableTokenCollection for Vec<CompressedBrainfuckToken> {
     }
 }
 
-impl CompressableTokenCollection for Vec<BrainfuckToken> {
-    fn compress(self: Self) -> Vec<CompressedBrainfuckToken> {
+impl CompressableTokenCollection for Vec<SpannedBrainfuckToken> {
+    fn compress(self) -> Vec<CompressedBrainfuckToken> {
         let mut compressed_tokens: Vec<CompressedBrainfuckToken> = Vec::new();
         let mut index: usize = 0;
         while index < self.len() {
             let currtoken = &self[index];
-            match self[index] {
-                BrainfuckToken::Out
-                | BrainfuckToken::Input
-                | BrainfuckToken::LoopStart
-                | BrainfuckToken::LoopEnd => {
+            match currtoken.token {
+                BrainfuckToken::Input | BrainfuckToken::LoopStart | BrainfuckToken::LoopEnd => {
                     compressed_tokens.push(CompressedBrainfuckToken {
-                        token: currtoken.to_owned(),
+                        token: currtoken.token,
                         num: 1,
+                        span: currtoken.span,
                     });
                     index += 1;
                     continue;
@@ -133,7 +220,7 @@ impl CompressableTokenCollection for Vec<BrainfuckToken> {
 
             let mut numtokens: u64 = 0;
             let mut subindex: usize = index;
-            while currtoken == &self[subindex] {
+            while currtoken.token == self[subindex].token {
                 numtokens += 1;
                 subindex += 1;
 
@@ -143,19 +230,20 @@ impl CompressableTokenCollection for Vec<BrainfuckToken> {
             }
 
             compressed_tokens.push(CompressedBrainfuckToken {
-                token: currtoken.to_owned(),
+                token: currtoken.token,
                 num: numtokens,
+                span: currtoken.span,
             });
 
-            index += subindex - index;
+            index = subindex;
         }
-        return compressed_tokens;
+        compressed_tokens
     }
 }
 
-impl Into<BrainfuckToken> for u8 {
-    fn into(self) -> BrainfuckToken {
-        match self {
+impl From<u8> for BrainfuckToken {
+    fn from(byte: u8) -> Self {
+        match byte {
             b'>' => BrainfuckToken::Next,
             b'<' => BrainfuckToken::Prev,
             b'+' => BrainfuckToken::Add,
@@ -170,10 +258,7 @@ impl Into<BrainfuckToken> for u8 {
 }
 
 impl BfToken for u8 {
-    fn is_valid_token(self: Self) -> bool {
-        match self {
-            b'>' | b'<' | b'+' | b'-' | b'.' | b',' | b'[' | b']' => true,
-            _ => false,
-        }
+    fn is_valid_token(&self) -> bool {
+        matches!(self, b'>' | b'<' | b'+' | b'-' | b'.' | b',' | b'[' | b']')
     }
 }
@@ -0,0 +1,524 @@
+//! WebAssembly text backend, for browsers and WASI runtimes
+//!
+//! The tape lives at the end of linear memory, after the output buffer and the constant data,
+//! so a growing tape can take the pages `memory.grow` adds. `,` and `.` go through the WASI
+//! `fd_read` and `fd_write` imports.
+
+use crate::runtime::OUTPUT_BUFFER_SIZE;
+use crate::{BfIr, Eof, QbfcError, Tape, TapeMode, OUT_OF_BOUNDS_EXIT_CODE};
+use std::fmt::Write;
+
+pub trait WatLowerable {
+    /// Fails when the tape does not fit in the 4 GiB a wasm32 memory can address
+    fn to_wat(&self, tape: Tape) -> Result<String, QbfcError>;
+}
+
+impl WatLowerable for [BfIr] {
+    fn to_wat(&self, tape: Tape) -> Result<String, QbfcError> {
+        let mut program = WatProgram {
+            tape,
+            body: String::new(),
+            depth: 2,
+            data: String::new(),
+            data_end: DATA_START,
+            labels: 0,
+            checks_bounds: false,
+        };
+        program.lower(self);
+        program.finish()
+    }
+}
+
+/// The `iovec` handed to WASI, followed by the byte count it reports back
+const IOVEC: u32 = 0;
+const IO_RESULT: u32 = 8;
+/// Where `fd_read` puts the byte `,` reads
+const INPUT_BYTE: u32 = 12;
+/// Room for the decimal digits of a source offset and a newline
+const DIGITS: u32 = 16;
+const DIGITS_END: u32 = DIGITS + 24;
+const OUTPUT_BUFFER: u32 = 64;
+const DATA_START: u32 = OUTPUT_BUFFER + OUTPUT_BUFFER_SIZE as u32;
+const PAGE_SIZE: u64 = 65536;
+const STDOUT_FILENO: u32 = 1;
+const STDERR_FILENO: u32 = 2;
+const OUT_OF_BOUNDS_MESSAGE: &[u8] = b"tape pointer out of bounds at source offset ";
+
+struct WatProgram {
+    tape: Tape,
+    /// Instructions of `_start`
+    body: String,
+    /// Indentation level of the next instruction
+    depth: usize,
+    /// Data segments of the bytes `Write` nodes output
+    data: String,
+    /// First free byte after the data segments
+    data_end: u32,
+    labels: usize,
+    /// Whether `$qbfc_out_of_bounds` or `$qbfc_grow_tape` has to be defined, and
+    /// `proc_exit` imported
+    checks_bounds: bool,
+}
+
+impl WatProgram {
+    fn line(&mut self, instr: &str) {
+        writeln!(self.body, "{}{}", "  ".repeat(self.depth), instr).unwrap();
+    }
+
+    fn fresh_label(&mut self) -> usize {
+        self.labels += 1;
+        self.labels
+    }
+
+    /// Places `bytes` in a data segment of their own and returns their address
+    fn add_data(&mut self, bytes: &[u8]) -> u32 {
+        let address = self.data_end;
+        writeln!(
+            self.data,
+            "  (data (i32.const {}) \"{}\")",
+            address,
+            escape_bytes(bytes)
+        )
+        .unwrap();
+        self.data_end += bytes.len() as u32;
+        address
+    }
+
+    fn lower(&mut self, program: &[BfIr]) {
+        for node in program {
+            match node {
+                BfIr::MovePtr { delta } => self.line(&format!(
+                    "(local.set $p (i32.add (local.get $p) (i32.const {})))",
+                    delta * self.tape.cell_bytes() as i64
+                )),
+                BfIr::AddCell { offset, delta } => {
+                    let sum = format!(
+                        "({}.add {} {})",
+                        self.value_type(),
+                        self.load(*offset),
+                        self.constant(*delta)
+                    );
+                    self.store(*offset, &sum);
+                }
+                BfIr::SetCell { offset, value } => {
+                    let value = self.constant(*value);
+                    self.store(*offset, &value);
+                }
+                BfIr::MulAdd {
+                    offset,
+                    src,
+                    factor,
+                } => {
+                    let ty = self.value_type();
+                    let product = match self.tape.wrap(*factor) {
+                        1 => self.load(*src),
+                        _ => format!(
+                            "({}.mul {} {})",
+                            ty,
+                            self.load(*src),
+                            self.constant(*factor)
+                        ),
+                    };
+                    let sum = format!("({}.add {} {})", ty, self.load(*offset), product);
+                    self.store(*offset, &sum);
+                }
+                BfIr::Scan { stride, .. } => {
+                    let scan = self.fresh_label();
+                    self.line(&format!("(block $scan_join.{}", scan));
+                    self.depth += 1;
+                    self.line(&format!("(loop $scan.{}", scan));
+                    self.depth += 1;
+                    let is_zero = format!("({}.eqz {})", self.value_type(), self.load(0));
+                    self.line(&format!("(br_if $scan_join.{} {})", scan, is_zero));
+                    self.lower(&[BfIr::MovePtr { delta: *stride }]);
+                    self.line(&format!("(br $scan.{})))", scan));
+                    self.depth -= 2;
+                }
+                BfIr::Loop { body, .. } => {
+                    // wasm cannot jump into a loop to its test, so the `if` tests the first
+                    // iteration and `br_if` at the bottom every one after it
+                    let looped = self.fresh_label();
+                    let nonzero = self.nonzero();
+                    self.line(&format!("(if {}", nonzero));
+                    self.depth += 1;
+                    self.line("(then");
+                    self.depth += 1;
+                    self.line(&format!("(loop $loop.{}", looped));
+                    self.depth += 1;
+                    self.lower(body);
+                    self.line(&format!("(br_if $loop.{} {}))))", looped, nonzero));
+                    self.depth -= 3;
+                }
+                BfIr::Output { offset, count } => {
+                    let value = self.load(*offset);
+                    let value = match self.tape.cell_bits {
+                        64 => format!("(i32.wrap_i64 {})", value),
+                        _ => value,
+                    };
+                    // Counts past what an i32 holds take more than one call
+                    let mut remaining = *count;
+                    while remaining > 0 {
+                        let chunk = remaining.min(i32::MAX as u64);
+                        self.line(&format!(
+                            "(call $qbfc_output {} (i32.const {}))",
+                            value, chunk
+                        ));
+                        remaining -= chunk;
+                    }
+                }
+                BfIr::Input { offset } => self.input(*offset),
+                BfIr::BoundsCheck { offset, source } => self.bounds_check(*offset, *source),
+                BfIr::Write { bytes } => {
+                    let address = self.add_data(bytes);
+                    self.line("(call $qbfc_flush)");
+                    self.line(&format!(
+                        "(call $qbfc_write (i32.const {}) (i32.const {}) (i32.const {}))",
+                        STDOUT_FILENO,
+                        address,
+                        bytes.len()
+                    ));
+                }
+            }
+        }
+    }
+
+    fn input(&mut self, offset: i64) {
+        // Output sits in linear memory until the buffer is written with `fd_write`
+        self.line("(call $qbfc_flush)");
+        self.line("(local.set $input (call $qbfc_read))");
+        let input = match self.tape.cell_bits {
+            64 => "(i64.extend_i32_s (local.get $input))",
+            _ => "(local.get $input)",
+        };
+        match self.tape.eof {
+            Eof::MinusOne => self.store(offset, input),
+            Eof::Zero => {
+                let value = format!(
+                    "(select {} {} (i32.ge_s (local.get $input) (i32.const 0)))",
+                    input,
+                    self.constant(0)
+                );
+                self.store(offset, &value);
+            }
+            Eof::Unchanged => {
+                self.line("(if (i32.ge_s (local.get $input) (i32.const 0))");
+                self.depth += 1;
+                self.line("(then");
+                self.depth += 1;
+                self.store(offset, input);
+                self.depth -= 2;
+                self.line("))");
+            }
+        }
+    }
+
+    /// Compares the address of the cell at `offset` against the tape globals, calling
+    /// `$qbfc_out_of_bounds` or `$qbfc_grow_tape` when it is off the tape
+    fn bounds_check(&mut self, offset: i64, source: usize) {
+        self.checks_bounds = true;
+        let address = format!(
+            "(i32.add (local.get $p) (i32.const {}))",
+            offset * self.tape.cell_bytes() as i64
+        );
+        // Comparing the signed distance from the start also catches addresses below zero
+        let below = format!(
+            "(i32.lt_s (i32.sub {} (global.get $tape_start)) (i32.const 0))",
+            address
+        );
+        let above = format!("(i32.ge_u {} (global.get $tape_end))", address);
+        let out_of_bounds = format!("(call $qbfc_out_of_bounds (i32.const {}))", source);
+        let grow = format!(
+            "(local.set $p (i32.add (local.get $p) (call $qbfc_grow_tape {})))",
+            address
+        );
+        match self.tape.mode {
+            TapeMode::Fixed => {
+                self.line(&format!("(if (i32.or {} {})", below, above));
+                self.line(&format!("  (then {}))", out_of_bounds));
+            }
+            TapeMode::Grow => {
+                self.line(&format!("(if {}", below));
+                self.line(&format!("  (then {}))", out_of_bounds));
+                self.line(&format!("(if {}", above));
+                self.line(&format!("  (then {}))", grow));
+            }
+            TapeMode::GrowBoth => {
+                self.line(&format!("(if (i32.or {} {})", below, above));
+                self.line(&format!("  (then {}))", grow));
+            }
+        }
+    }
+
+    /// Type cell values are computed in, 64-bit cells do not fit in an i32
+    fn value_type(&self) -> &'static str {
+        match self.tape.cell_bits {
+            64 => "i64",
+            _ => "i32",
+        }
+    }
+
+    fn constant(&self, value: i64) -> String {
+        match self.tape.cell_bits {
+            64 => format!("(i64.const {})", value),
+            _ => format!("(i32.const {})", self.tape.wrap(value) as u32 as i32),
+        }
+    }
+
+    /// Address and static offset of the cell `offset` cells away from the tape pointer, the
+    /// static offset of a memory access can not be negative
+    fn memarg(&self, offset: i64) -> (String, String) {
+        let bytes = offset * self.tape.cell_bytes() as i64;
+        match bytes {
+            0 => ("(local.get $p)".to_owned(), String::new()),
+            1.. => ("(local.get $p)".to_owned(), format!(" offset={}", bytes)),
+            _ => (
+                format!(
+                    "(i32.sub (local.get $p) (i32.const {}))",
+                    bytes.unsigned_abs()
+                ),
+                String::new(),
+            ),
+        }
+    }
+
+    fn load(&self, offset: i64) -> String {
+        let (address, memarg) = self.memarg(offset);
+        let load = match self.tape.cell_bits {
+            8 => "i32.load8_u",
+            16 => "i32.load16_u",
+            32 => "i32.load",
+            _ => "i64.load",
+        };
+        format!("({}{} {})", load, memarg, address)
+    }
+
+    fn store(&mut self, offset: i64, value: &str) {
+        let (address, memarg) = self.memarg(offset);
+        let store = match self.tape.cell_bits {
+            8 => "i32.store8",
+            16 => "i32.store16",
+            32 => "i32.store",
+            _ => "i64.store",
+        };
+        self.line(&format!("({}{} {} {})", store, memarg, address, value));
+    }
+
+    /// i32 that is nonzero when the current cell is
+    fn nonzero(&self) -> String {
+        match self.tape.cell_bits {
+            64 => format!("(i64.ne {} (i64.const 0))", self.load(0)),
+            _ => self.load(0),
+        }
+    }
+
+    fn finish(mut self) -> Result<String, QbfcError> {
+        let message = if self.checks_bounds && self.tape.mode != TapeMode::GrowBoth {
+            self.add_data(OUT_OF_BOUNDS_MESSAGE)
+        } else {
+            0
+        };
+        let tape_start = self.data_end.next_multiple_of(8);
+        let tape_end = tape_start as u64 + self.tape.size as u64 * self.tape.cell_bytes();
+        // Addresses are i32 constants, so the end of the tape has to be one too
+        if tape_end > u64::from(u32::MAX) {
+            return Err(QbfcError::Usage(format!(
+                "the tape needs {} bytes of memory, more than wasm32 can address",
+                tape_end
+            )));
+        }
+        let pages = tape_end.div_ceil(PAGE_SIZE);
+
+        let mut module = String::from(
+            "(module\n  \
+             (import \"wasi_snapshot_preview1\" \"fd_read\"\n    \
+             (func $fd_read (param i32 i32 i32 i32) (result i32)))\n  \
+             (import \"wasi_snapshot_preview1\" \"fd_write\"\n    \
+             (func $fd_write (param i32 i32 i32 i32) (result i32)))\n",
+        );
+        if self.checks_bounds && self.tape.mode != TapeMode::GrowBoth {
+            module.push_str(
+                "  (import \"wasi_snapshot_preview1\" \"proc_exit\"\n    \
+                 (func $proc_exit (param i32)))\n",
+            );
+        }
+        writeln!(module, "  (memory (export \"memory\") {})", pages).unwrap();
+        let mutable = |ty: &str| match self.tape.mode {
+            TapeMode::Fixed => ty.to_owned(),
+            TapeMode::Grow | TapeMode::GrowBoth => format!("(mut {})", ty),
+        };
+        writeln!(
+            module,
+            "  (global $tape_start {} (i32.const {}))\n  \
+             (global $tape_end {} (i32.const {}))\n  \
+             (global $output_length (mut i32) (i32.const 0))",
+            mutable("i32"),
+            tape_start,
+            mutable("i32"),
+            tape_end
+        )
+        .unwrap();
+        module.push_str(&self.data);
+        module.push_str(&io_routines());
+        if self.checks_bounds && self.tape.mode != TapeMode::GrowBoth {
+            module.push_str(&out_of_bounds_routine(message));
+        }
+        if self.checks_bounds && self.tape.mode != TapeMode::Fixed {
+            module.push_str(&grow_routine(self.tape));
+        }
+        module.push_str(
+            "  (func (export \"_start\")\n    \
+             (local $p i32) (local $input i32)\n    \
+             (local.set $p (global.get $tape_start))\n",
+        );
+        module.push_str(&self.body);
+        module.push_str("    (call $qbfc_flush)))\n");
+        Ok(module)
+    }
+}
+
+/// `$qbfc_write` writes a whole range to a file descriptor, `$qbfc_output` appends repeated
+/// bytes to the output buffer that `$qbfc_flush` writes out, and `$qbfc_read` returns the next
+/// input byte or -1
+fn io_routines() -> String {
+    format!(
+        "  (func $qbfc_write (param $fd i32) (param $at i32) (param $left i32)
+    (block $done
+      (loop $write
+        (br_if $done (i32.eqz (local.get $left)))
+        (i32.store (i32.const {iovec}) (local.get $at))
+        (i32.store (i32.const {iovec_len}) (local.get $left))
+        (br_if $done
+          (call $fd_write (local.get $fd) (i32.const {iovec}) (i32.const 1) (i32.const {result})))
+        (local.set $at (i32.add (local.get $at) (i32.load (i32.const {result}))))
+        (local.set $left (i32.sub (local.get $left) (i32.load (i32.const {result}))))
+        (br $write))))
+  (func $qbfc_flush
+    (call $qbfc_write (i32.const {stdout}) (i32.const {buffer}) (global.get $output_length))
+    (global.set $output_length (i32.const 0)))
+  (func $qbfc_output (param $byte i32) (param $count i32)
+    (loop $append
+      (if (i32.eq (global.get $output_length) (i32.const {size}))
+        (then (call $qbfc_flush)))
+      (i32.store8 offset={buffer} (global.get $output_length) (local.get $byte))
+      (global.set $output_length (i32.add (global.get $output_length) (i32.const 1)))
+      (br_if $append
+        (local.tee $count (i32.sub (local.get $count) (i32.const 1))))))
+  (func $qbfc_read (result i32)
+    (i32.store (i32.const {iovec}) (i32.const {input}))
+    (i32.store (i32.const {iovec_len}) (i32.const 1))
+    (if (i32.or
+          (call $fd_read (i32.const 0) (i32.const {iovec}) (i32.const 1) (i32.const {result}))
+          (i32.eqz (i32.load (i32.const {result}))))
+      (then (return (i32.const -1))))
+    (i32.load8_u (i32.const {input})))
+",
+        iovec = IOVEC,
+        iovec_len = IOVEC + 4,
+        result = IO_RESULT,
+        input = INPUT_BYTE,
+        stdout = STDOUT_FILENO,
+        buffer = OUTPUT_BUFFER,
+        size = OUTPUT_BUFFER_SIZE,
+    )
+}
+
+/// `$qbfc_out_of_bounds(source)` reports the offending source offset on stderr and exits,
+/// after whatever output is still buffered
+fn out_of_bounds_routine(message: u32) -> String {
+    format!(
+        "  (func $qbfc_out_of_bounds (param $source i32)
+    (local $at i32)
+    (call $qbfc_flush)
+    (call $qbfc_write (i32.const {stderr}) (i32.const {message}) (i32.const {length}))
+    (local.set $at (i32.const {newline}))
+    (i32.store8 (local.get $at) (i32.const 10))
+    (loop $digit
+      (local.set $at (i32.sub (local.get $at) (i32.const 1)))
+      (i32.store8 (local.get $at)
+        (i32.add (i32.const 48) (i32.rem_u (local.get $source) (i32.const 10))))
+      (br_if $digit (local.tee $source (i32.div_u (local.get $source) (i32.const 10)))))
+    (call $qbfc_write (i32.const {stderr}) (local.get $at)
+      (i32.sub (i32.const {digits_end}) (local.get $at)))
+    (call $proc_exit (i32.const {code}))
+    (unreachable))
+",
+        stderr = STDERR_FILENO,
+        length = OUT_OF_BOUNDS_MESSAGE.len(),
+        newline = DIGITS_END - 1,
+        digits_end = DIGITS_END,
+        code = OUT_OF_BOUNDS_EXIT_CODE,
+    )
+}
+
+/// `$qbfc_grow_tape(address)` grows the tape so that `address` lands on it, taking more pages
+/// when the memory runs out, zeroes the new cells and returns how far the old cells moved.
+/// It grows by `Tape::growth`.
+fn grow_routine(tape: Tape) -> String {
+    let mut routine = String::from(
+        "  (func $qbfc_grow_tape (param $address i32) (result i32)
+    (local $length i32) (local $index i32) (local $added i32) (local $new_end i32)
+    (local.set $length (i32.sub (global.get $tape_end) (global.get $tape_start)))
+    (local.set $index (i32.sub (local.get $address) (global.get $tape_start)))
+",
+    );
+    // `memory.copy` handles the overlap of the old and the moved cells
+    if tape.mode == TapeMode::GrowBoth {
+        routine.push_str(
+            "    (if (i32.lt_s (local.get $index) (i32.const 0))
+      (then
+        (local.set $added (i32.sub (i32.const 0) (local.get $index)))
+        (if (i32.lt_u (local.get $added) (local.get $length))
+          (then (local.set $added (local.get $length))))
+        (call $qbfc_reserve (i32.add (global.get $tape_end) (local.get $added)))
+        (memory.copy
+          (i32.add (global.get $tape_start) (local.get $added))
+          (global.get $tape_start)
+          (local.get $length))
+        (memory.fill (global.get $tape_start) (i32.const 0) (local.get $added))
+        (global.set $tape_end (i32.add (global.get $tape_end) (local.get $added)))
+        (return (local.get $added))))
+",
+        );
+    }
+    // Measured in bytes, so the index is rounded up to the end of its cell
+    write!(
+        routine,
+        "    (local.set $added (i32.sub (i32.add (local.get $index) (i32.const {cell_bytes}))
+      (local.get $length)))
+    (if (i32.lt_u (local.get $added) (local.get $length))
+      (then (local.set $added (local.get $length))))
+    (local.set $new_end (i32.add (global.get $tape_end) (local.get $added)))
+    (call $qbfc_reserve (local.get $new_end))
+    (memory.fill (global.get $tape_end) (i32.const 0) (local.get $added))
+    (global.set $tape_end (local.get $new_end))
+    (i32.const 0))
+  (func $qbfc_reserve (param $end i32)
+    (local $pages i32)
+    (local.set $pages
+      (i32.sub
+        (i32.div_u (i32.add (local.get $end) (i32.const {page_mask})) (i32.const {page_size}))
+        (memory.size)))
+    (if (i32.gt_s (local.get $pages) (i32.const 0))
+      (then
+        (if (i32.lt_s (memory.grow (local.get $pages)) (i32.const 0))
+          (then (unreachable))))))
+",
+        cell_bytes = tape.cell_bytes(),
+        page_mask = PAGE_SIZE - 1,
+        page_size = PAGE_SIZE,
+    )
+    .unwrap();
+    routine
+}
+
+/// Spells `bytes` out for a WAT string, anything that is not plain printable ASCII becomes a
+/// hexadecimal escape
+fn escape_bytes(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|&byte| match byte {
+            b' '..=b'~' if byte != b'"' && byte != b'\\' => (byte as char).to_string(),
+            _ => format!("\\{:02x}", byte),
+        })
+        .collect()
+}
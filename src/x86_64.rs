@@ -0,0 +1,488 @@
+//! x86-64 GNU assembler backend, for building without QBE
+//!
+//! The tape pointer lives in `%rbx` and the bounds of the tape in `%r12` and `%r13`, all of them
+//! callee-saved so they survive the calls into libc. `%r14` counts repeated output.
+
+use crate::ir::escape_bytes;
+use crate::{BfIr, Eof, Tape, TapeMode, OUT_OF_BOUNDS_EXIT_CODE};
+use std::fmt::Write;
+
+pub trait X86_64Lowerable {
+    fn to_x86_64(&self, tape: Tape) -> String;
+}
+
+impl X86_64Lowerable for [BfIr] {
+    fn to_x86_64(&self, tape: Tape) -> String {
+        let mut program = X86_64Program {
+            tape,
+            text: String::new(),
+            stubs: String::new(),
+            rodata: String::new(),
+            labels: 0,
+            checks_bounds: false,
+        };
+        program.prologue();
+        program.lower(self);
+        program.epilogue()
+    }
+}
+
+struct X86_64Program {
+    tape: Tape,
+    /// Body of `main`
+    text: String,
+    /// Out of line paths of the bounds checks, placed after the body of `main`
+    stubs: String,
+    rodata: String,
+    labels: usize,
+    /// Whether `qbfc_out_of_bounds` or `qbfc_grow_tape` has to be emitted
+    checks_bounds: bool,
+}
+
+/// Registers `main` saves, five of them also keep the stack aligned for calls
+const SAVED_REGISTERS: [&str; 5] = ["%rbx", "%r12", "%r13", "%r14", "%r15"];
+const TAPE_NAME: &str = "qbfc_tape";
+const GROW_NAME: &str = "qbfc_grow_tape";
+const OUT_OF_BOUNDS_NAME: &str = "qbfc_out_of_bounds";
+
+impl X86_64Program {
+    fn instr(&mut self, instr: &str) {
+        writeln!(self.text, "\t{}", instr).unwrap();
+    }
+
+    fn label(&mut self, label: &str) {
+        writeln!(self.text, "{}:", label).unwrap();
+    }
+
+    fn fresh_label(&mut self) -> usize {
+        self.labels += 1;
+        self.labels
+    }
+
+    fn prologue(&mut self) {
+        self.text
+            .push_str("\t.text\n\t.globl main\n\t.type main, @function\nmain:\n");
+        for register in SAVED_REGISTERS {
+            self.instr(&format!("pushq {}", register));
+        }
+        let tape_bytes = self.tape.size as u64 * self.tape.cell_bytes();
+        if self.tape.mode == TapeMode::Fixed {
+            self.instr(&format!("leaq {}(%rip), %r12", TAPE_NAME));
+        } else {
+            self.load_const("%rdi", self.tape.size as i64);
+            self.load_const("%rsi", self.tape.cell_bytes() as i64);
+            self.instr("call calloc@PLT");
+            self.instr("movq %rax, %r12");
+        }
+        self.instr("movq %r12, %r13");
+        self.add_to_register("%r13", tape_bytes as i64);
+        self.instr("movq %r12, %rbx");
+    }
+
+    fn epilogue(mut self) -> String {
+        self.instr("xorl %eax, %eax");
+        for register in SAVED_REGISTERS.iter().rev() {
+            self.instr(&format!("popq {}", register));
+        }
+        self.instr("ret");
+        let mut source = self.text;
+        source.push_str(&self.stubs);
+        source.push_str("\t.size main, .-main\n");
+
+        if self.checks_bounds && self.tape.mode != TapeMode::GrowBoth {
+            source.push_str(&out_of_bounds_routine());
+            self.rodata.push_str(
+                ".Lout_of_bounds_message:\n\
+                 \t.string \"tape pointer out of bounds at source offset %lu\\n\"\n",
+            );
+        }
+        if self.checks_bounds && self.tape.mode != TapeMode::Fixed {
+            source.push_str(&grow_routine(self.tape));
+        }
+        if !self.rodata.is_empty() {
+            source.push_str("\t.section .rodata\n");
+            source.push_str(&self.rodata);
+        }
+        if self.tape.mode == TapeMode::Fixed {
+            writeln!(
+                source,
+                "\t.bss\n\t.p2align 3\n{}:\n\t.zero {}",
+                TAPE_NAME,
+                self.tape.size as u64 * self.tape.cell_bytes()
+            )
+            .unwrap();
+        }
+        source.push_str("\t.section .note.GNU-stack,\"\",@progbits\n");
+        source
+    }
+
+    fn lower(&mut self, program: &[BfIr]) {
+        for node in program {
+            match node {
+                BfIr::MovePtr { delta } => {
+                    self.add_to_register("%rbx", delta * self.tape.cell_bytes() as i64)
+                }
+                BfIr::AddCell { offset, delta } => self.op_const("add", *delta, *offset),
+                BfIr::SetCell { offset, value } => self.op_const("mov", *value, *offset),
+                BfIr::MulAdd {
+                    offset,
+                    src,
+                    factor,
+                } => {
+                    self.load_scratch(*src);
+                    let (suffix, scratch) = (self.suffix(), self.scratch());
+                    match self.tape.wrap(*factor) {
+                        1 => self.instr(&format!(
+                            "add{} {}, {}",
+                            suffix,
+                            scratch,
+                            cell(*offset, &self.tape)
+                        )),
+                        factor if factor == self.tape.wrap(-1) => self.instr(&format!(
+                            "sub{} {}, {}",
+                            suffix,
+                            scratch,
+                            cell(*offset, &self.tape)
+                        )),
+                        _ => {
+                            // The scratch register is at least 32 bits wide, multiplying in it
+                            // wraps the same way once stored back into the cell
+                            if self.tape.cell_bits == 64 && i32::try_from(*factor).is_err() {
+                                self.instr(&format!("movabsq ${}, %rcx", factor));
+                                self.instr("imulq %rcx, %rax");
+                            } else if self.tape.cell_bits == 64 {
+                                self.instr(&format!("imulq ${}, %rax, %rax", factor));
+                            } else {
+                                self.instr(&format!(
+                                    "imull ${}, %eax, %eax",
+                                    self.tape.wrap(*factor) as u32 as i32
+                                ));
+                            }
+                            self.instr(&format!(
+                                "add{} {}, {}",
+                                suffix,
+                                scratch,
+                                cell(*offset, &self.tape)
+                            ));
+                        }
+                    }
+                }
+                BfIr::Scan { stride: 1, source } if self.tape.scans_bytes() => {
+                    self.instr("movq %rbx, %rdi");
+                    self.instr("xorl %esi, %esi");
+                    self.instr("movq %r13, %rdx");
+                    self.instr("subq %rbx, %rdx");
+                    self.instr("call memchr@PLT");
+                    // Without a zero cell the scan runs off the end of the tape
+                    self.instr("testq %rax, %rax");
+                    self.instr("cmovzq %r13, %rax");
+                    self.instr("movq %rax, %rbx");
+                    self.bounds_check(0, *source);
+                }
+                BfIr::Scan { stride, .. } => {
+                    let scan = self.fresh_label();
+                    self.instr(&format!("jmp .Lscan_cond.{}", scan));
+                    self.label(&format!(".Lscan_body.{}", scan));
+                    self.add_to_register("%rbx", stride * self.tape.cell_bytes() as i64);
+                    self.label(&format!(".Lscan_cond.{}", scan));
+                    self.instr(&format!("cmp{} $0, (%rbx)", self.suffix()));
+                    self.instr(&format!("jne .Lscan_body.{}", scan));
+                }
+                BfIr::Loop { body, .. } => {
+                    // Testing at the bottom takes one branch per iteration instead of two
+                    let looped = self.fresh_label();
+                    self.instr(&format!("jmp .Lloop_cond.{}", looped));
+                    self.label(&format!(".Lloop_body.{}", looped));
+                    self.lower(body);
+                    self.label(&format!(".Lloop_cond.{}", looped));
+                    self.instr(&format!("cmp{} $0, (%rbx)", self.suffix()));
+                    self.instr(&format!("jne .Lloop_body.{}", looped));
+                }
+                BfIr::Output { offset, count } => self.output(*offset, *count),
+                BfIr::Input { offset } => self.input(*offset),
+                BfIr::BoundsCheck { offset, source } => self.bounds_check(*offset, *source),
+                BfIr::Write { bytes } => {
+                    let data = self.fresh_label();
+                    writeln!(
+                        self.rodata,
+                        ".Lbytes.{}:\n\t.ascii \"{}\"",
+                        data,
+                        escape_bytes(bytes)
+                    )
+                    .unwrap();
+                    self.instr(&format!("leaq .Lbytes.{}(%rip), %rdi", data));
+                    self.instr("movl $1, %esi");
+                    self.load_const("%rdx", bytes.len() as i64);
+                    self.instr("movq stdout@GOTPCREL(%rip), %rcx");
+                    self.instr("movq (%rcx), %rcx");
+                    self.instr("call fwrite@PLT");
+                }
+            }
+        }
+    }
+
+    fn output(&mut self, offset: i64, count: u64) {
+        let load = format!("movzbl {}, %edi", cell(offset, &self.tape));
+        if count == 1 {
+            self.instr(&load);
+            self.instr("call putchar@PLT");
+            return;
+        }
+        let output = self.fresh_label();
+        self.load_const("%r14", count as i64);
+        self.label(&format!(".Loutput.{}", output));
+        self.instr(&load);
+        self.instr("call putchar@PLT");
+        self.instr("decq %r14");
+        self.instr(&format!("jnz .Loutput.{}", output));
+    }
+
+    fn input(&mut self, offset: i64) {
+        // fflush(NULL) flushes stdout without loading it through the GOT
+        self.instr("xorl %edi, %edi");
+        self.instr("call fflush@PLT");
+        self.instr("call getchar@PLT");
+        let input = self.fresh_label();
+        match self.tape.eof {
+            Eof::MinusOne => (),
+            Eof::Zero => {
+                self.instr("testl %eax, %eax");
+                self.instr(&format!("jns .Linput_store.{}", input));
+                self.instr("xorl %eax, %eax");
+            }
+            Eof::Unchanged => {
+                self.instr("testl %eax, %eax");
+                self.instr(&format!("js .Linput_join.{}", input));
+            }
+        }
+        self.label(&format!(".Linput_store.{}", input));
+        // Sign extends %eax so EOF fills all of a 64-bit cell
+        if self.tape.cell_bits == 64 {
+            self.instr("cltq");
+        }
+        self.instr(&format!(
+            "mov{} {}, {}",
+            self.suffix(),
+            self.scratch(),
+            cell(offset, &self.tape)
+        ));
+        self.label(&format!(".Linput_join.{}", input));
+    }
+
+    /// Compares the address of the cell at `offset` against %r12 and %r13, jumping to an out of
+    /// line stub that stops the program or grows the tape when it is off the tape
+    fn bounds_check(&mut self, offset: i64, source: usize) {
+        self.checks_bounds = true;
+        let check = self.fresh_label();
+        let out_of_bounds = format!(".Lout_of_bounds.{}", check);
+        let grow = format!(".Lgrow.{}", check);
+        let in_bounds = format!(".Lin_bounds.{}", check);
+        let (below, above) = match self.tape.mode {
+            TapeMode::Fixed => (&out_of_bounds, &out_of_bounds),
+            TapeMode::Grow => (&out_of_bounds, &grow),
+            TapeMode::GrowBoth => (&grow, &grow),
+        };
+        self.instr(&format!("leaq {}, %rdi", cell(offset, &self.tape)));
+        self.instr("cmpq %r12, %rdi");
+        self.instr(&format!("jb {}", below));
+        self.instr("cmpq %r13, %rdi");
+        self.instr(&format!("jae {}", above));
+        self.label(&in_bounds);
+
+        if self.tape.mode != TapeMode::GrowBoth {
+            write!(
+                self.stubs,
+                "{}:\n\tmovq ${}, %rdi\n\tcall {}\n",
+                out_of_bounds, source, OUT_OF_BOUNDS_NAME
+            )
+            .unwrap();
+        }
+        if self.tape.mode != TapeMode::Fixed {
+            write!(
+                self.stubs,
+                "{}:\n\tcall {}\n\tjmp {}\n",
+                grow, GROW_NAME, in_bounds
+            )
+            .unwrap();
+        }
+    }
+
+    /// Adds `value` to the cell at `offset`, or moves it there for `mov`, going through
+    /// `%rax` when it does not fit an immediate
+    fn op_const(&mut self, op: &str, value: i64, offset: i64) {
+        let suffix = self.suffix();
+        let destination = cell(offset, &self.tape);
+        if self.tape.cell_bits < 64 {
+            let value = self.tape.wrap(value);
+            self.instr(&format!("{}{} ${}, {}", op, suffix, value, destination));
+        } else if i32::try_from(value).is_ok() {
+            self.instr(&format!("{}q ${}, {}", op, value, destination));
+        } else {
+            self.instr(&format!("movabsq ${}, %rax", value));
+            self.instr(&format!("{}q %rax, {}", op, destination));
+        }
+    }
+
+    fn add_to_register(&mut self, register: &str, value: i64) {
+        if value == 0 {
+            return;
+        }
+        if i32::try_from(value).is_ok() {
+            self.instr(&format!("addq ${}, {}", value, register));
+        } else {
+            self.instr(&format!("movabsq ${}, %rax", value));
+            self.instr(&format!("addq %rax, {}", register));
+        }
+    }
+
+    fn load_const(&mut self, register: &str, value: i64) {
+        if i32::try_from(value).is_ok() {
+            self.instr(&format!("movq ${}, {}", value, register));
+        } else {
+            self.instr(&format!("movabsq ${}, {}", value, register));
+        }
+    }
+
+    /// Loads the cell at `offset` into the scratch register, zero extended to at least 32 bits
+    fn load_scratch(&mut self, offset: i64) {
+        let source = cell(offset, &self.tape);
+        self.instr(&match self.tape.cell_bits {
+            8 => format!("movzbl {}, %eax", source),
+            16 => format!("movzwl {}, %eax", source),
+            32 => format!("movl {}, %eax", source),
+            _ => format!("movq {}, %rax", source),
+        });
+    }
+
+    /// Operand size suffix of a cell
+    fn suffix(&self) -> char {
+        match self.tape.cell_bits {
+            8 => 'b',
+            16 => 'w',
+            32 => 'l',
+            _ => 'q',
+        }
+    }
+
+    /// The part of `%rax` as wide as a cell
+    fn scratch(&self) -> &'static str {
+        match self.tape.cell_bits {
+            8 => "%al",
+            16 => "%ax",
+            32 => "%eax",
+            _ => "%rax",
+        }
+    }
+}
+
+/// Memory operand of the cell `offset` cells away from the tape pointer
+fn cell(offset: i64, tape: &Tape) -> String {
+    match offset * tape.cell_bytes() as i64 {
+        0 => "(%rbx)".to_owned(),
+        displacement => format!("{}(%rbx)", displacement),
+    }
+}
+
+/// `qbfc_out_of_bounds(source)` reports the offending source offset and exits, after whatever
+/// output is still buffered
+fn out_of_bounds_routine() -> String {
+    format!(
+        "\t.type {name}, @function\n\
+         {name}:\n\
+         \tpushq %rbx\n\
+         \tmovq %rdi, %rbx\n\
+         \txorl %edi, %edi\n\
+         \tcall fflush@PLT\n\
+         \tmovl $2, %edi\n\
+         \tleaq .Lout_of_bounds_message(%rip), %rsi\n\
+         \tmovq %rbx, %rdx\n\
+         \txorl %eax, %eax\n\
+         \tcall dprintf@PLT\n\
+         \tmovl ${code}, %edi\n\
+         \tcall exit@PLT\n\
+         \t.size {name}, .-{name}\n",
+        name = OUT_OF_BOUNDS_NAME,
+        code = OUT_OF_BOUNDS_EXIT_CODE,
+    )
+}
+
+/// `qbfc_grow_tape(address)` reallocates the tape so that `address` lands on it and zeroes the
+/// new cells, growing by `Tape::growth`. It updates the tape registers of `main`
+/// directly, moving `%rbx` along with the cells.
+fn grow_routine(tape: Tape) -> String {
+    let mut routine = format!(
+        "\t.type {name}, @function\n\
+         {name}:\n\
+         \tpushq %r14\n\
+         \tpushq %r15\n\
+         \tsubq $8, %rsp\n\
+         \tmovq %r13, %r14\n\
+         \tsubq %r12, %r14\n\
+         \tsubq %r12, %rbx\n\
+         \tmovq %rdi, %rax\n\
+         \tsubq %r12, %rax\n",
+        name = GROW_NAME,
+    );
+    if tape.mode == TapeMode::GrowBoth {
+        routine.push_str("\tjs .Lgrow_left\n");
+    }
+    // %r15 is the new length in bytes, %r14 the old one
+    write!(
+        routine,
+        "\tleaq {}(%rax), %r15\n\
+         \tleaq (%r14,%r14), %rax\n\
+         \tcmpq %rax, %r15\n\
+         \tcmovbq %rax, %r15\n\
+         \tmovq %r12, %rdi\n\
+         \tmovq %r15, %rsi\n\
+         \tcall realloc@PLT\n\
+         \tmovq %rax, %r12\n\
+         \tleaq (%r12,%r14), %rdi\n\
+         \txorl %esi, %esi\n\
+         \tmovq %r15, %rdx\n\
+         \tsubq %r14, %rdx\n\
+         \tcall memset@PLT\n\
+         \tleaq (%r12,%r15), %r13\n",
+        tape.cell_bytes()
+    )
+    .unwrap();
+    // %r15 is how far the old cells move, which is also what %rbx moves by
+    if tape.mode == TapeMode::GrowBoth {
+        routine.push_str(
+            "\tjmp .Lgrow_done\n\
+             .Lgrow_left:\n\
+             \tnegq %rax\n\
+             \tmovq %rax, %r15\n\
+             \tcmpq %r14, %r15\n\
+             \tcmovbq %r14, %r15\n\
+             \tmovq %r12, %rdi\n\
+             \tleaq (%r14,%r15), %rsi\n\
+             \tcall realloc@PLT\n\
+             \tmovq %rax, %r12\n\
+             \tleaq (%r12,%r15), %rdi\n\
+             \tmovq %r12, %rsi\n\
+             \tmovq %r14, %rdx\n\
+             \tcall memmove@PLT\n\
+             \tmovq %r12, %rdi\n\
+             \txorl %esi, %esi\n\
+             \tmovq %r15, %rdx\n\
+             \tcall memset@PLT\n\
+             \tleaq (%r14,%r15), %r13\n\
+             \taddq %r12, %r13\n\
+             \taddq %r15, %rbx\n\
+             .Lgrow_done:\n",
+        );
+    }
+    write!(
+        routine,
+        "\taddq %r12, %rbx\n\
+         \taddq $8, %rsp\n\
+         \tpopq %r15\n\
+         \tpopq %r14\n\
+         \tret\n\
+         \t.size {name}, .-{name}\n",
+        name = GROW_NAME,
+    )
+    .unwrap();
+    routine
+}
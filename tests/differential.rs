@@ -0,0 +1,921 @@
+//! Runs every program in `tests/programs` through the interpreter and, for every backend whose
+//! tools are installed, through the compiled binary, comparing stdout and exit codes.
+//!
+//! A program `name.bf` is fed `name.in` on stdin when it exists, and the interpreter output is
+//! checked against `name.out` when that exists.
+
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+const QBFC: &str = env!("CARGO_BIN_EXE_qbfc");
+
+fn corpus() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/programs");
+    let mut programs: Vec<PathBuf> = fs::read_dir(dir)
+        .expect("Failed reading the test corpus")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "bf"))
+        .collect();
+    programs.sort();
+    programs
+}
+
+fn on_path(binary: &str) -> bool {
+    env::var_os("PATH")
+        .is_some_and(|paths| env::split_paths(&paths).any(|dir| dir.join(binary).is_file()))
+}
+
+fn run_with_input(command: &mut Command, input: &[u8]) -> Output {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed spawning the program");
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn input_for(program: &Path) -> Vec<u8> {
+    fs::read(program.with_extension("in")).unwrap_or_default()
+}
+
+fn interpret(program: &Path) -> Output {
+    run_with_input(
+        Command::new(QBFC).arg(program).args(["--type", "run"]),
+        &input_for(program),
+    )
+}
+
+#[test]
+fn interpreter_matches_expected_output() {
+    for program in corpus() {
+        let Ok(expected) = fs::read(program.with_extension("out")) else {
+            continue;
+        };
+        let output = interpret(&program);
+        assert!(
+            output.status.success(),
+            "{} failed: {}",
+            program.display(),
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert_eq!(output.stdout, expected, "{}", program.display());
+    }
+}
+
+/// Runs the WASI module named by its only argument on node's WASI, passing on the exit code
+const WASI_RUNNER: &str = "
+const { WASI } = require('node:wasi');
+const wasi = new WASI({ version: 'preview1', returnOnExit: true });
+const wasm = new WebAssembly.Module(require('node:fs').readFileSync(process.argv[1]));
+process.exitCode = wasi.start(new WebAssembly.Instance(wasm, wasi.getImportObject()));
+";
+
+/// The ways qbfc can turn a program into something that runs
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Backend {
+    Qbe,
+    X86_64,
+    C,
+    Wat,
+}
+
+impl Backend {
+    /// Backends whose tools are installed
+    fn available() -> Vec<Backend> {
+        let mut backends = vec![];
+        if on_path("qbe") && on_path("cc") {
+            backends.push(Backend::Qbe);
+        }
+        if on_path("cc") && cfg!(all(target_arch = "x86_64", target_os = "linux")) {
+            backends.push(Backend::X86_64);
+        }
+        if on_path("cc") {
+            backends.push(Backend::C);
+        }
+        if on_path("node") {
+            backends.push(Backend::Wat);
+        }
+        backends
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Backend::Qbe => "qbe",
+            Backend::X86_64 => "x86-64",
+            Backend::C => "c",
+            Backend::Wat => "wat",
+        }
+    }
+
+    /// Command running what `build` built
+    fn command(self, binary: &Path) -> Command {
+        match self {
+            Backend::Wat => {
+                let mut node = Command::new("node");
+                node.args(["--no-warnings", "-e", WASI_RUNNER]).arg(binary);
+                node
+            }
+            _ => Command::new(binary),
+        }
+    }
+
+    /// Compiles `program` with `flags` into the binary, or WebAssembly module, `name` and
+    /// returns its path
+    fn build(self, program: &Path, flags: &[&str], outdir: &str, name: &str) -> PathBuf {
+        let outdir = Path::new(env!("CARGO_TARGET_TMPDIR"))
+            .join(outdir)
+            .join(self.name());
+        fs::create_dir_all(&outdir).unwrap();
+        let binary = outdir.join(name);
+        let source = binary.with_extension(self.name());
+        let mut qbfc = Command::new(QBFC);
+        qbfc.arg(program).args(flags).arg("--output");
+        match self {
+            Backend::Qbe => qbfc.arg(&binary),
+            Backend::X86_64 => qbfc.arg(&binary).args(["--backend", "x86-64"]),
+            Backend::C => qbfc.arg(&source).args(["--type", "c"]),
+            Backend::Wat => qbfc.arg(&source).args(["--type", "wat"]),
+        };
+        let build = qbfc.output().unwrap();
+        assert!(
+            build.status.success(),
+            "{} failed to compile with {}: {}",
+            program.display(),
+            self.name(),
+            String::from_utf8_lossy(&build.stderr)
+        );
+        if let Backend::C = self {
+            let build = Command::new("cc")
+                .args(["-O2", "-std=c99", "-o"])
+                .arg(&binary)
+                .arg(&source)
+                .output()
+                .unwrap();
+            assert!(
+                build.status.success(),
+                "{} failed to compile: {}",
+                source.display(),
+                String::from_utf8_lossy(&build.stderr)
+            );
+        }
+        if let Backend::Wat = self {
+            let module = binary.with_extension("wasm");
+            fs::write(&module, assemble_wat(&source)).unwrap();
+            return module;
+        }
+        binary
+    }
+}
+
+/// Turns the text module at `path` into a binary one, checking that it is valid
+fn assemble_wat(path: &Path) -> Vec<u8> {
+    let module = wat::parse_file(path)
+        .unwrap_or_else(|err| panic!("{} does not parse: {}", path.display(), err));
+    wasmparser::Validator::new()
+        .validate_all(&module)
+        .unwrap_or_else(|err| panic!("{} is not valid: {}", path.display(), err));
+    module
+}
+
+#[test]
+fn compiled_matches_interpreter() {
+    let backends = Backend::available();
+    if backends.is_empty() {
+        eprintln!("skipping compiled programs, cc or node is needed on PATH");
+        return;
+    }
+
+    for backend in backends {
+        for program in corpus() {
+            let name = program.file_stem().unwrap().to_str().unwrap();
+            let binary = backend.build(&program, &[], "differential", name);
+            let compiled = run_with_input(&mut backend.command(&binary), &input_for(&program));
+            let interpreted = interpret(&program);
+            assert_eq!(
+                compiled.stdout,
+                interpreted.stdout,
+                "{} output differs with {}",
+                program.display(),
+                backend.name()
+            );
+            assert_eq!(
+                compiled.status.code(),
+                interpreted.status.code(),
+                "{} exit code differs with {}",
+                program.display(),
+                backend.name()
+            );
+        }
+    }
+}
+
+#[test]
+fn wat_modules_are_valid() {
+    let outdir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("wat");
+    fs::create_dir_all(&outdir).unwrap();
+    for flags in [
+        &[][..],
+        &["--checked"],
+        &["--tape", "grow", "--cell-bits", "16"],
+        &["--tape", "grow-both", "--cell-bits", "64", "--eof", "zero"],
+        &["--no-optimize", "--cell-bits", "32", "--eof", "unchanged"],
+    ] {
+        for program in corpus() {
+            let module = outdir
+                .join(program.file_stem().unwrap())
+                .with_extension("wat");
+            let build = Command::new(QBFC)
+                .arg(&program)
+                .args(flags)
+                .args(["--type", "wat", "--output"])
+                .arg(&module)
+                .output()
+                .unwrap();
+            assert!(build.status.success(), "{}", program.display());
+            assemble_wat(&module);
+        }
+    }
+}
+
+#[test]
+fn wat_tapes_fit_in_wasm32_memory() {
+    let outdir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("wat");
+    fs::create_dir_all(&outdir).unwrap();
+    let program = outdir.join("huge.bf");
+    fs::write(&program, ",+.").unwrap();
+    let module = outdir.join("huge.wat");
+    let build = |size: &str| {
+        Command::new(QBFC)
+            .arg(&program)
+            .args([
+                "--tape-size",
+                size,
+                "--cell-bits",
+                "64",
+                "--type",
+                "wat",
+                "--output",
+            ])
+            .arg(&module)
+            .output()
+            .unwrap()
+    };
+
+    // Just short of the 65536 pages a wasm32 memory can have
+    assert!(build("536000000").status.success());
+    assemble_wat(&module);
+
+    let output = build("1000000000");
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("wasm32"));
+}
+
+/// Writes `source` to a scratch file and runs it through the interpreter with `flags`, and
+/// through the binary of every available backend
+fn run_source(name: &str, source: &str, flags: &[&str]) -> (Output, Vec<Output>) {
+    let outdir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("options");
+    fs::create_dir_all(&outdir).unwrap();
+    let program = outdir.join(name).with_extension("bf");
+    fs::write(&program, source).unwrap();
+
+    let interpreted = run_with_input(
+        Command::new(QBFC)
+            .arg(&program)
+            .args(["--type", "run"])
+            .args(flags),
+        &[],
+    );
+    let compiled = Backend::available()
+        .into_iter()
+        .map(|backend| {
+            let binary = backend.build(&program, flags, "options", name);
+            run_with_input(&mut backend.command(&binary), &[])
+        })
+        .collect();
+    (interpreted, compiled)
+}
+
+#[test]
+fn tape_size_is_configurable() {
+    let source = format!("{}{}.", ">".repeat(40000), "+".repeat(49));
+
+    let (default_tape, _) = run_source("far_default", &source, &[]);
+    assert!(!default_tape.status.success());
+
+    let (interpreted, compiled) = run_source("far", &source, &["--tape-size", "40001"]);
+    assert_eq!(interpreted.stdout, b"1");
+    for compiled in compiled {
+        assert_eq!(compiled.stdout, b"1");
+    }
+}
+
+#[test]
+fn cell_bits_set_the_wrapping_point() {
+    // Builds 256, 65536 and 2^32 by repeated multiplication and prints a 1 for each one that
+    // does not wrap around to zero
+    let one = "+".repeat(49);
+    let source = format!(
+        "{}[>{}<-]>[>{}>+<<-]>>[[-]>{one}.[-]<]<[>>>{}>+<<<<-]>>>>[[-]>{one}.[-]<]<[>{one}.>]",
+        "+".repeat(16),
+        "+".repeat(16),
+        "+".repeat(256),
+        "+".repeat(65536),
+    );
+    for (bits, expected) in [("8", ""), ("16", "1"), ("32", "11"), ("64", "111")] {
+        let (interpreted, compiled) =
+            run_source(&format!("width{}", bits), &source, &["--cell-bits", bits]);
+        assert_eq!(interpreted.stdout, expected.as_bytes(), "{} bits", bits);
+        for compiled in compiled {
+            assert_eq!(
+                compiled.stdout,
+                expected.as_bytes(),
+                "{} bits compiled",
+                bits
+            );
+        }
+    }
+}
+
+#[test]
+fn negative_constants_in_64_bit_cells() {
+    // Sets a cell to -1 and multiplies by -2, both past i64::MAX as unsigned cell values
+    let source = ",+[-]-.>++[->--<]>.";
+    let (interpreted, compiled) = run_source("negative64", source, &["--cell-bits", "64"]);
+    assert_eq!(interpreted.stdout, b"\xff\xfc");
+    for compiled in compiled {
+        assert_eq!(compiled.stdout, b"\xff\xfc");
+    }
+
+    // QBE saturates integer literals that do not fit in an i64
+    let program = Path::new(env!("CARGO_TARGET_TMPDIR")).join("options/negative64.bf");
+    let output = Command::new(QBFC)
+        .arg(&program)
+        .args(["--cell-bits", "64", "--type", "sst", "--output", "-"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let il = String::from_utf8(output.stdout).unwrap();
+    for literal in il
+        .split(|c: char| !c.is_ascii_digit())
+        .filter(|word| !word.is_empty())
+    {
+        assert!(literal.parse::<i64>().is_ok(), "{}", literal);
+    }
+}
+
+#[test]
+fn eof_modes() {
+    let source = format!("{},.", "+".repeat(65));
+    for (mode, expected) in [("unchanged", b"A"), ("zero", b"\0"), ("minus-one", b"\xff")] {
+        let (interpreted, compiled) =
+            run_source(&format!("eof_{}", mode), &source, &["--eof", mode]);
+        assert_eq!(interpreted.stdout, expected, "{}", mode);
+        for compiled in compiled {
+            assert_eq!(compiled.stdout, expected, "{} compiled", mode);
+        }
+    }
+}
+
+#[test]
+fn checked_programs_stop_at_the_tape_edge() {
+    let (interpreted, compiled) = run_source("walk", "+[>+]", &["--checked"]);
+    assert_eq!(interpreted.status.code(), Some(3));
+    for compiled in compiled {
+        assert_eq!(compiled.status.code(), Some(3));
+        assert_eq!(
+            String::from_utf8_lossy(&compiled.stderr),
+            "tape pointer out of bounds at source offset 2\n"
+        );
+    }
+
+    // Leaving the tape is enough, the cell off it does not have to be touched
+    let (interpreted, compiled) = run_source("step_off", "+.<", &["--checked"]);
+    assert_eq!(interpreted.stdout, b"\x01");
+    assert_eq!(interpreted.status.code(), Some(3));
+    for compiled in compiled {
+        assert_eq!(compiled.stdout, b"\x01");
+        assert_eq!(compiled.status.code(), Some(3));
+    }
+}
+
+#[test]
+fn scans_stop_at_the_end_of_a_full_tape() {
+    // Every cell is -1, so `memchr` and `memrchr` find no zero and the scan runs off the tape
+    // at the `[`, even without --checked. Scans that do not use them are left alone.
+    let outdir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("options");
+    fs::create_dir_all(&outdir).unwrap();
+    for (name, source, source_offset, backends) in [
+        (
+            "scan_right",
+            ",>,>,>,<<<[>]",
+            10,
+            &[Backend::Qbe, Backend::X86_64, Backend::C][..],
+        ),
+        ("scan_left", ",>,>,>,[<]", 7, &[Backend::Qbe]),
+    ] {
+        let program = outdir.join(name).with_extension("bf");
+        fs::write(&program, source).unwrap();
+        let flags = ["--tape-size", "4"];
+        let interpreted = run_with_input(
+            Command::new(QBFC)
+                .arg(&program)
+                .args(["--type", "run"])
+                .args(flags),
+            &[],
+        );
+        assert_eq!(interpreted.status.code(), Some(3), "{}", name);
+        assert!(bfir_of(source).contains("scan"), "{}", name);
+
+        for backend in Backend::available() {
+            if !backends.contains(&backend) {
+                continue;
+            }
+            let binary = backend.build(&program, &flags, "options", name);
+            let compiled = run_with_input(&mut backend.command(&binary), &[]);
+            assert_eq!(compiled.status.code(), Some(3), "{} {:?}", name, backend);
+            assert_eq!(
+                String::from_utf8_lossy(&compiled.stderr),
+                format!("tape pointer out of bounds at source offset {source_offset}\n"),
+                "{} {:?}",
+                name,
+                backend
+            );
+        }
+    }
+}
+
+#[test]
+fn growing_tapes() {
+    let right = format!("{}{}.", ">".repeat(100), "+".repeat(49));
+    let left = format!(
+        "{}{}{}.{}.",
+        "+".repeat(49),
+        "<".repeat(100),
+        "+".repeat(50),
+        ">".repeat(100)
+    );
+    for (name, source, mode, expected, code) in [
+        ("grow_right", right.as_str(), "grow", &b"1"[..], Some(0)),
+        ("grow_both", &left, "grow-both", b"21", Some(0)),
+        ("grow_left", &left, "grow", b"", Some(3)),
+        // Stepping off the left end stops the program even though no cell there is touched
+        ("step_left", "+.<", "grow", b"\x01", Some(3)),
+    ] {
+        let (interpreted, compiled) =
+            run_source(name, source, &["--tape", mode, "--tape-size", "4"]);
+        assert_eq!(interpreted.stdout, expected, "{}", name);
+        assert_eq!(interpreted.status.code(), code, "{}", name);
+        for compiled in compiled {
+            assert_eq!(compiled.stdout, expected, "{} compiled", name);
+            assert_eq!(compiled.status.code(), code, "{} compiled", name);
+        }
+    }
+}
+
+#[test]
+fn apple_targets_scan_without_memrchr() {
+    let outdir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("targets");
+    fs::create_dir_all(&outdir).unwrap();
+    let program = outdir.join("scan_left.bf");
+    fs::write(&program, ",>+>+[<].").unwrap();
+
+    for (target, uses_memrchr) in [
+        ("amd64_sysv", true),
+        ("arm64", true),
+        ("rv64", true),
+        ("amd64_apple", false),
+        ("arm64_apple", false),
+    ] {
+        let output = Command::new(QBFC)
+            .arg(&program)
+            .args(["--type", "sst", "--output", "-", "--target", target])
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{}", target);
+        let il = String::from_utf8(output.stdout).unwrap();
+        assert_eq!(il.contains("$memrchr"), uses_memrchr, "{}", target);
+    }
+}
+
+/// Writes a shell script standing in for a toolchain command, it records its arguments in
+/// `name.args` and whatever comes in on stdin in `name.stdin`
+#[cfg(unix)]
+fn fake_tool(outdir: &Path, name: &str) -> PathBuf {
+    use std::os::unix::fs::PermissionsExt;
+
+    let script = outdir.join(name);
+    fs::write(
+        &script,
+        format!(
+            "#!/bin/sh\nfor arg in \"$@\"; do echo \"$arg\"; done > {0}.args\ncat > {0}.stdin\n",
+            script.display()
+        ),
+    )
+    .unwrap();
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+    script
+}
+
+#[cfg(unix)]
+#[test]
+fn toolchain_is_configurable() {
+    let outdir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("toolchain");
+    fs::create_dir_all(&outdir).unwrap();
+    let program = outdir.join("hello.bf");
+    fs::write(&program, "++++++++[>++++++++<-]>+.").unwrap();
+    let qbe = fake_tool(&outdir, "fake-qbe");
+    let cc = fake_tool(&outdir, "fake-cc");
+    let binary = outdir.join("hello");
+    let args_of = |tool: &Path| -> Vec<String> {
+        fs::read_to_string(tool.with_extension("args"))
+            .unwrap()
+            .lines()
+            .map(str::to_owned)
+            .collect()
+    };
+
+    let status = Command::new(QBFC)
+        .arg(&program)
+        .args(["--backend", "x86-64", "--output"])
+        .arg(&binary)
+        .arg("--cc")
+        .arg(&cc)
+        .args([
+            "--cflags",
+            "-g -Wa,--noexecstack",
+            "--ldflags",
+            "-static -lm",
+        ])
+        .env_remove("QBFC_CC")
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let binary = binary.to_str().unwrap();
+    assert_eq!(
+        args_of(&cc),
+        [
+            "-g",
+            "-Wa,--noexecstack",
+            "-x",
+            "assembler",
+            "-",
+            "-x",
+            "none",
+            "-static",
+            "-lm",
+            "-o",
+            binary
+        ]
+    );
+    let assembly = fs::read_to_string(cc.with_extension("stdin")).unwrap();
+    assert!(assembly.contains("main:"));
+
+    // The environment names the tools when the flags do not
+    let status = Command::new(QBFC)
+        .arg(&program)
+        .args(["--output", binary])
+        .env("QBFC_QBE", &qbe)
+        .env("QBFC_CC", &cc)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let il = fs::read_to_string(qbe.with_extension("stdin")).unwrap();
+    assert!(il.contains("export function w $main()"));
+    assert_eq!(
+        args_of(&cc),
+        ["-x", "assembler", "-", "-x", "none", "-o", binary]
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn failures_have_their_own_exit_codes() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let outdir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("failures");
+    fs::create_dir_all(&outdir).unwrap();
+    let program = outdir.join("hello.bf");
+    fs::write(&program, "+.").unwrap();
+    let unmatched = outdir.join("unmatched.bf");
+    fs::write(&unmatched, "+[.").unwrap();
+    let failing = outdir.join("failing-qbe");
+    fs::write(
+        &failing,
+        "#!/bin/sh\ncat > /dev/null\necho 'qbe: invalid IL' >&2\nexit 1\n",
+    )
+    .unwrap();
+    fs::set_permissions(&failing, fs::Permissions::from_mode(0o755)).unwrap();
+    let qbfc = |program: &Path, args: &[&str]| {
+        Command::new(QBFC)
+            .arg(program)
+            .args(["--output", outdir.join("out").to_str().unwrap()])
+            .args(args)
+            .env_remove("QBFC_QBE")
+            .env_remove("QBFC_CC")
+            .output()
+            .unwrap()
+    };
+
+    let cases: [(&Path, &[&str], i32); 5] = [
+        (&unmatched, &[], 1),
+        (&program, &["--backend", "x86-64", "--target", "arm64"], 2),
+        (&outdir.join("missing.bf"), &[], 4),
+        (&program, &["--qbe", "/nonexistent/qbe"], 5),
+        (&program, &["--qbe", failing.to_str().unwrap()], 6),
+    ];
+    for (program, args, code) in cases {
+        let output = qbfc(program, args);
+        assert_eq!(output.status.code(), Some(code), "{:?}", args);
+        assert!(String::from_utf8_lossy(&output.stderr).contains("error: "));
+    }
+
+    // What the tool said is passed on
+    let output = qbfc(&program, &["--qbe", failing.to_str().unwrap()]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("qbe: invalid IL"));
+}
+
+#[test]
+fn library_matches_the_cli() {
+    let program = Path::new("tests/programs/hello.bf");
+    let options = qbfc::Options {
+        backend: qbfc::Backend::X86_64,
+        ..Default::default()
+    };
+    let tokens = qbfc::tokenize(&fs::read(program).unwrap());
+    let bfir = qbfc::optimize(&tokens, &options).unwrap();
+    let cli = |args: &[&str]| {
+        let output = Command::new(QBFC)
+            .arg(program)
+            .args(["--output", "-"])
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        output.stdout
+    };
+
+    assert_eq!(
+        format!("{}\n", qbfc::lower_to_qbe(&bfir, &options).unwrap()).into_bytes(),
+        cli(&["--type", "sst"])
+    );
+    assert_eq!(
+        qbfc::emit_asm(&bfir, &options).unwrap(),
+        cli(&["--type", "asm", "--backend", "x86-64"])
+    );
+
+    let mut interpreted = vec![];
+    qbfc::interpret(&tokens, &options, &mut &b""[..], &mut interpreted).unwrap();
+    assert_eq!(interpreted, cli(&["--type", "run"]));
+
+    let unmatched = qbfc::tokenize(b"+[");
+    assert!(matches!(
+        qbfc::optimize(&unmatched, &options),
+        Err(qbfc::QbfcError::UnmatchedBrackets(_))
+    ));
+
+    // Tapes the command line cannot ask for are refused rather than miscompiled
+    for (size, cell_bits) in [(0, 8), (16, 12), (16, 0)] {
+        let options = qbfc::Options {
+            tape: qbfc::Tape {
+                size,
+                cell_bits,
+                ..Default::default()
+            },
+            ..options.clone()
+        };
+        let usage = |result| matches!(result, Err(qbfc::QbfcError::Usage(_)));
+        assert!(usage(qbfc::optimize(&tokens, &options).map(drop)));
+        assert!(usage(qbfc::interpret(
+            &tokens,
+            &options,
+            &mut &b""[..],
+            &mut vec![]
+        )));
+        assert!(usage(qbfc::emit_c(&bfir, &options).map(drop)));
+        assert!(usage(qbfc::emit_wat(&bfir, &options).map(drop)));
+        assert!(usage(qbfc::lower_to_qbe(&bfir, &options).map(drop)));
+        assert!(usage(qbfc::emit_asm(&bfir, &options).map(drop)));
+    }
+}
+
+#[test]
+fn several_inputs() {
+    let outdir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("inputs");
+    let _ = fs::remove_dir_all(&outdir);
+    fs::create_dir_all(&outdir).unwrap();
+    let setup = outdir.join("setup.bf");
+    fs::write(&setup, "++++++++[>++++++++<-]>").unwrap();
+    let print = outdir.join("print.bf");
+    fs::write(&print, "+.\n").unwrap();
+    let broken = outdir.join("broken.bf");
+    fs::write(&broken, "+\n+[").unwrap();
+    let qbfc = |files: &[&Path], args: &[&str], stdin: &[u8]| {
+        run_with_input(Command::new(QBFC).args(files).args(args), stdin)
+    };
+
+    // Files are one program unless --out-dir is given, - is stdin
+    let output = qbfc(&[&setup, &print], &["--type", "run"], b"");
+    assert_eq!(output.stdout, b"A");
+    let listing = ["--type", "bfir", "--output", "-"];
+    let output = qbfc(
+        &[Path::new("-"), &print],
+        &listing,
+        b"++++++++[>++++++++<-]>",
+    );
+    assert!(output.status.success());
+    assert_eq!(output.stdout, qbfc(&[&setup, &print], &listing, b"").stdout);
+
+    // Diagnostics point into the file they are about
+    let output = qbfc(&[&setup, &broken], &["--type", "run"], b"");
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("broken.bf:2:2"), "{}", stderr);
+
+    let compiled = outdir.join("compiled");
+    let output = qbfc(
+        &[&setup, &print, Path::new("-")],
+        &[
+            "--type",
+            "bfir",
+            "--no-optimize",
+            "--out-dir",
+            compiled.to_str().unwrap(),
+        ],
+        b"-",
+    );
+    assert!(output.status.success());
+    for name in ["setup.bfir", "print.bfir", "stdin.bfir"] {
+        assert!(compiled.join(name).exists(), "{}", name);
+    }
+    assert!(!fs::read_to_string(compiled.join("print.bfir"))
+        .unwrap()
+        .contains("loop"));
+
+    // Nothing may be read from stdin twice or compiled over another output, and a program read
+    // from stdin cannot also take its input from there
+    let other = outdir.join("other");
+    fs::create_dir_all(&other).unwrap();
+    let same_stem = other.join("print.bf");
+    fs::write(&same_stem, "+.").unwrap();
+    let out_dir = ["--out-dir", compiled.to_str().unwrap()];
+    for (files, args) in [
+        (
+            &[Path::new("-"), Path::new("-")][..],
+            &["--type", "run"][..],
+        ),
+        (&[Path::new("-")][..], &["--type", "run"][..]),
+        (&[&print, &same_stem], &out_dir),
+    ] {
+        let output = qbfc(files, args, b"");
+        assert_eq!(output.status.code(), Some(2), "{:?}", files);
+        assert!(String::from_utf8_lossy(&output.stderr).starts_with("error: "));
+    }
+}
+
+/// The BfIr listing qbfc prints for `source`, which starts with `,` so nothing before the
+/// loops under test is folded into constant output
+fn bfir_of(source: &str) -> String {
+    let output = run_with_input(
+        Command::new(QBFC).args(["-", "--type", "bfir", "--output", "-"]),
+        source.as_bytes(),
+    );
+    assert!(output.status.success(), "{}", source);
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn clear_loops_become_sets() {
+    for source in [",[-]", ",[+]", ",[---]"] {
+        assert_eq!(bfir_of(source), "input [p]\nset [p] 0\n", "{}", source);
+    }
+    // An even step can miss zero on an odd cell, and a loop that moves is no clear loop
+    for source in [",[--]", ",[-<]"] {
+        let listing = bfir_of(source);
+        assert!(listing.contains("loop {"), "{}", source);
+        assert!(!listing.contains("set"), "{}", source);
+    }
+}
+
+#[test]
+fn multiply_loops_become_muladds() {
+    assert_eq!(
+        bfir_of(",[->+>++<<]"),
+        "input [p]\nmuladd [p+1] [p] *1\nmuladd [p+2] [p] *2\nset [p] 0\n"
+    );
+    // Loops that do not come back to where they started move the pointer every iteration
+    for source in [",[->+<<]", ",[->>+<]"] {
+        let listing = bfir_of(source);
+        assert!(listing.contains("loop {"), "{}", source);
+        assert!(!listing.contains("muladd"), "{}", source);
+    }
+}
+
+#[test]
+fn scan_loops_become_scans() {
+    for (source, stride) in [(",[>]", "+1"), (",[<<<]", "-3"), (",[>>>>]", "+4")] {
+        assert_eq!(
+            bfir_of(source),
+            format!("input [p]\nscan {}\n", stride),
+            "{}",
+            source
+        );
+    }
+    // A loop that also changes cells is no scan
+    for source in [",[>+]", ",[>-]"] {
+        let listing = bfir_of(source);
+        assert!(listing.contains("loop {"), "{}", source);
+        assert!(!listing.contains("scan"), "{}", source);
+    }
+}
+
+#[test]
+fn pointer_movement_folds_into_offsets() {
+    assert_eq!(bfir_of(",>>+<<."), "input [p]\nadd [p+2] +1\noutput [p]\n");
+    assert_eq!(bfir_of(",>>,<.<"), "input [p]\ninput [p+2]\noutput [p+1]\n");
+    // Inside a loop that does not come back the moves add up to one at the end of the body
+    assert_eq!(
+        bfir_of(",[->+<<]"),
+        "input [p]\nloop {\n    add [p] -1\n    add [p+1] +1\n    move -1\n}\n"
+    );
+}
+
+/// What qbfc prints on stderr when it rejects `source` read from stdin
+fn diagnostics_of(source: &str) -> String {
+    let output = run_with_input(
+        Command::new(QBFC).args(["-", "--type", "bfir", "--output", "-"]),
+        source.as_bytes(),
+    );
+    assert_eq!(output.status.code(), Some(1), "{}", source);
+    String::from_utf8(output.stderr).unwrap()
+}
+
+#[test]
+fn diagnostics_point_a_caret_at_the_bracket() {
+    // The tab before the bracket is kept so the caret lines up in any terminal
+    assert_eq!(
+        diagnostics_of("+\n\t+[ [-]\n"),
+        "error: unmatched `[`, this loop is never closed\n \
+         --> <stdin>:2:3\n  \
+         |\n\
+         2 | \t+[ [-]\n  \
+         | \t ^\n\
+         \n\
+         error: aborting due to 1 unmatched bracket(s)\n"
+    );
+}
+
+#[test]
+fn every_unmatched_bracket_is_reported() {
+    let locations = |stderr: &str| -> Vec<String> {
+        stderr
+            .lines()
+            .filter_map(|line| line.strip_prefix(" --> <stdin>:"))
+            .map(str::to_owned)
+            .collect()
+    };
+
+    let stderr = diagnostics_of("+]\n[[-]\n]]][\n[");
+    assert_eq!(locations(&stderr), ["1:2", "3:2", "3:3", "3:4", "4:1"]);
+    assert!(stderr.ends_with("error: aborting due to 5 unmatched bracket(s)\n"));
+
+    // A `]` before a `[` closes nothing and the `[` after it is still open
+    let stderr = diagnostics_of("][");
+    assert_eq!(locations(&stderr), ["1:1", "1:2"]);
+    assert!(stderr.contains("unmatched `]`, there is no open loop to close"));
+    assert!(stderr.contains("unmatched `[`, this loop is never closed"));
+}
+
+#[cfg(unix)]
+#[test]
+fn host_target_links_with_cc() {
+    if !cfg!(all(target_arch = "x86_64", target_os = "linux")) {
+        return;
+    }
+    let outdir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("host_cc");
+    fs::create_dir_all(&outdir).unwrap();
+    let program = outdir.join("hello.bf");
+    fs::write(&program, "+.").unwrap();
+    let cc = fake_tool(&outdir, "cc");
+    let _ = fs::remove_file(cc.with_extension("args"));
+    let path = format!(
+        "{}:{}",
+        outdir.display(),
+        env::var("PATH").unwrap_or_default()
+    );
+
+    let status = Command::new(QBFC)
+        .arg(&program)
+        .args(["--backend", "x86-64", "--target", "amd64_sysv", "--output"])
+        .arg(outdir.join("hello"))
+        .env("PATH", path)
+        .env_remove("QBFC_CC")
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert!(cc.with_extension("args").exists());
+}
@@ -0,0 +1,5 @@
+Prints five thousand letters A in runs of fifty then a newline
+++++++++++[>++++++++++<-]
+>>+++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++
+<[>..................................................<-]
+>>++++++++++.
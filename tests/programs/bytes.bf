@@ -0,0 +1,2 @@
+Prints a quote and a backslash and a zero byte and byte 255 then a newline which all need escaping once folded
+++++++++++++++++++++++++++++++++++.[-]++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++++.[-].-.+++++++++++.
@@ -0,0 +1,7 @@
+Runs that cancel each other out and loops next to each other
+++++++++[>++++++++<-]>+ 65 is A
+[-][-] clearing twice must not merge the loops
++++++++++[<++++++++>-]< 72 is H
+.>+-<>+<-+.
++><-<>.
+-+-+->><<+-+.
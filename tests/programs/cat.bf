@@ -0,0 +1,2 @@
+Copies its input to the output until EOF
+,+[-.,+]
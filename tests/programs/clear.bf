@@ -0,0 +1,7 @@
+Clear loops reset a cell no matter its value then the digits 0 to 4 get printed
++++++[-]++++++++++++++++++++++++++++++++++++++++++++++++.
+---[+]+++++++++++++++++++++++++++++++++++++++++++++++++.
+++[---]++++++++++++++++++++++++++++++++++++++++++++++++++.
+[-][-]+++++++++++++++++++++++++++++++++++++++++++++++++++.
+>+++[[-]<+>]<[-]++++++++++++++++++++++++++++++++++++++++++++++++++++.
+[-]++++++++++.
@@ -0,0 +1,2 @@
+Reading past the end of the input stores 255 in the cell
+,.,.
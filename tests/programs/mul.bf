@@ -0,0 +1,6 @@
+Multiplication and copy loops with wraparound
+++++++[>++++++++<-]>.   six times eight is the digit zero
+[->+>++<<]>>.           copies it once and twice for a backtick
+<[>+++++<-]>.           adds five times the copy which wraps to a capital P
+<---[+>-<]>.            counting up from minus three takes three away for a capital M
+[-]++++++++++.
@@ -0,0 +1,5 @@
+Prints the digits 0 to 9 followed by a newline using nested loops
+++++++++[>++++++<-]      cell 1 is 48
+++++++++++               cell 0 counts the digits
+[>.+<-[>>+<<-]>>[<<+>>-]<<]
+++++++++++.
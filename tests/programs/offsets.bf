@@ -0,0 +1,6 @@
+Reads two bytes into neighbouring cells and prints them back swapped and then shifted by one
+>>,<,                    cell 2 then cell 1
+>.<.                     prints them swapped
+>>>+<<<                  touches cell 4 without moving for good
+>+.<+.                   both shifted by one
+<++++++++++.
@@ -0,0 +1,6 @@
+Prints a prompt then echoes a character of input and a letter computed before the prompt
+++++++++[>++++++++<-]>+
+>++++++[>++++++++++<-]>++.
+<<
+>>>,.<<<.
+>>>>++++++++++.
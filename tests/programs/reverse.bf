@@ -0,0 +1,2 @@
+Reads everything up to EOF and prints it back in reverse order
+>,+[->,+]<[.<]
@@ -0,0 +1,11 @@
+Scan loops walk the tape until they find a zero cell and the digit printed is where they stopped
+>+>+>+>+<<<              cells 1 to 4 hold one and the pointer is on cell 1
+[>]                      stops on cell 5
++++++++++++++++++++++++++++++++++++++++++++++++++++++.[-]
+<[<]                     back to cell 0
+++++++++++++++++++++++++++++++++++++++++++++++++.[-]
+>[>>]                    cells 1 and 3 then stops on cell 5
++++++++++++++++++++++++++++++++++++++++++++++++++++++.[-]
+<<[<<<]                  cell 3 then stops on cell 0
+++++++++++++++++++++++++++++++++++++++++++++++++.[-]
+++++++++++.
@@ -0,0 +1,3 @@
+Cells are bytes: 0 minus 1 is 255 and 255 plus 1 is 0
+-.+.
+++++++++++.